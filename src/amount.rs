@@ -0,0 +1,106 @@
+use std::fmt;
+use std::str::FromStr;
+
+pub const COIN_DECIMALS: usize = 8;
+pub const COIN: u64 = 100_000_000;
+pub const MAX_MONEY: u64 = 21_000_000 * COIN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub fn from_base_units(units: u64) -> Option<Amount> {
+        if units > MAX_MONEY {
+            return None;
+        }
+
+        Some(Amount(units))
+    }
+
+    pub fn from_coins(coins: u64) -> Option<Amount> {
+        Amount::from_base_units(coins.checked_mul(COIN)?)
+    }
+
+    pub fn base_units(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        Amount::from_base_units(self.0.checked_add(other.0)?)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        Some(Amount(self.0.checked_sub(other.0)?))
+    }
+
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |acc, amount| acc.checked_add(amount))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let coins = self.0 / COIN;
+        let fraction = self.0 % COIN;
+
+        if fraction == 0 {
+            return write!(f, "{}", coins);
+        }
+
+        let fraction = format!("{:0width$}", fraction, width = COIN_DECIMALS);
+        write!(f, "{}.{}", coins, fraction.trim_end_matches('0'))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Amount, String> {
+        let s = s.trim();
+        let (coins, fraction) = match s.find('.') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => (s, ""),
+        };
+
+        if coins.is_empty() && fraction.is_empty() {
+            return Err(format!("invalid amount '{}'", s));
+        }
+
+        if !coins.chars().all(|c| c.is_ascii_digit())
+            || !fraction.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(format!(
+                "invalid amount '{}', only non-negative decimals are allowed",
+                s
+            ));
+        }
+
+        if fraction.len() > COIN_DECIMALS {
+            return Err(format!(
+                "invalid amount '{}', at most {} decimal places are allowed",
+                s, COIN_DECIMALS
+            ));
+        }
+
+        let coins = if coins.is_empty() {
+            0
+        } else {
+            coins
+                .parse::<u64>()
+                .map_err(|_| format!("amount '{}' is too large", s))?
+        };
+        let fraction = format!("{:0<width$}", fraction, width = COIN_DECIMALS)
+            .parse::<u64>()
+            .map_err(|_| format!("invalid amount '{}'", s))?;
+
+        coins
+            .checked_mul(COIN)
+            .and_then(|units| units.checked_add(fraction))
+            .and_then(Amount::from_base_units)
+            .ok_or_else(|| format!("amount '{}' exceeds the supply limit", s))
+    }
+}
@@ -12,10 +12,30 @@ pub struct Block {
     hash: String,
     nonce: u64,
     height: i32,
+    utxo_commitment: String,
+}
+
+// Everything of a block but its transactions, kept after the body is pruned.
+// The merkle roots commit to the transactions, so the proof of work covers them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    timestamp: u64,
+    prev_block_hash: String,
+    tx_root: String,
+    witness_root: String,
+    hash: String,
+    nonce: u64,
+    height: i32,
+    utxo_commitment: String,
 }
 
 impl Block {
-    pub fn new(transactions: Vec<Transaction>, prev_block_hash: &str, height: i32) -> Block {
+    pub fn new(
+        transactions: Vec<Transaction>,
+        prev_block_hash: &str,
+        height: i32,
+        utxo_commitment: &str,
+    ) -> Block {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .expect("SystemTime before UNIX EPOCH!");
@@ -26,6 +46,7 @@ impl Block {
             hash: String::new(),
             nonce: 0,
             height,
+            utxo_commitment: utxo_commitment.to_string(),
         };
         let pow = ProofOfWork::new(&block);
         let (nonce, hash) = pow.run();
@@ -34,8 +55,8 @@ impl Block {
         block
     }
 
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new(vec![coinbase], "", 0)
+    pub fn new_genesis_block(coinbase: Transaction, utxo_commitment: &str) -> Block {
+        Block::new(vec![coinbase], "", 0, utxo_commitment)
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -46,15 +67,39 @@ impl Block {
         bincode::deserialize(&bytes[..]).expect("error decerializing block")
     }
 
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            prev_block_hash: self.prev_block_hash.clone(),
+            tx_root: self.hash_transactions(),
+            witness_root: self.hash_witnesses(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            height: self.height,
+            utxo_commitment: self.utxo_commitment.clone(),
+        }
+    }
+
     pub fn hash_transactions(&self) -> String {
         let mut transactions: Vec<Vec<u8>> = Vec::new();
 
         for tx in &self.transactions {
-            transactions.push(tx.serialize());
+            transactions.push(tx.id().as_bytes().to_vec());
         }
 
         let tree = MerkleTree::new(&mut transactions);
-        String::from_utf8_lossy(&tree.data()[..]).to_string()
+        tree.data().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn hash_witnesses(&self) -> String {
+        let mut witnesses: Vec<Vec<u8>> = Vec::new();
+
+        for tx in &self.transactions {
+            witnesses.push(tx.witness_hash().as_bytes().to_vec());
+        }
+
+        let tree = MerkleTree::new(&mut witnesses);
+        tree.data().iter().map(|b| format!("{:02x}", b)).collect()
     }
 
     pub fn timestamp(&self) -> u64 {
@@ -73,6 +118,45 @@ impl Block {
         &self.hash[..]
     }
 
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    // MuHash of the UTXO set after this block is connected
+    pub fn utxo_commitment(&self) -> &str {
+        &self.utxo_commitment[..]
+    }
+}
+
+impl BlockHeader {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(&self).expect("error serializing block header")
+    }
+
+    pub fn deserialize(bytes: Vec<u8>) -> BlockHeader {
+        bincode::deserialize(&bytes[..]).expect("error decerializing block header")
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn prev_block_hash(&self) -> &str {
+        &self.prev_block_hash[..]
+    }
+
+    pub fn tx_root(&self) -> &str {
+        &self.tx_root[..]
+    }
+
+    pub fn witness_root(&self) -> &str {
+        &self.witness_root[..]
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash[..]
+    }
+
     pub fn nonce(&self) -> u64 {
         self.nonce
     }
@@ -80,4 +164,8 @@ impl Block {
     pub fn height(&self) -> i32 {
         self.height
     }
+
+    pub fn utxo_commitment(&self) -> &str {
+        &self.utxo_commitment[..]
+    }
 }
@@ -1,31 +1,68 @@
-use crate::block::Block;
-use crate::transaction::{TXOutputs, Transaction};
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
+use crate::datadir::DataDir;
+use crate::policy::Satisfier;
+use crate::proofofwork::ProofOfWork;
+use crate::storage::Store;
+use crate::transaction::{self, Transaction};
+use crate::utxo_set::{self, UTXOSet};
 
-use std::collections::HashMap;
-use typedb::{value, KV};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use typedb::value;
 
 value!(
     enum StoreValue {
         String(String),
         Block(Vec<u8>),
+        // Hash of the block holding a transaction and its position there
+        TxLocation(String, usize),
+        Header(Vec<u8>),
+        // Prune target and the height up to which block bodies are gone
+        Prune(PruneTarget, i32),
     }
 );
 
 const TIP_KEY: &str = "l";
 const CHECK_KEY: &str = "check_key";
 const CHECK_VALUE: &str = "check_value";
+const HEIGHT_PREFIX: &str = "height_";
+const TX_INDEX_KEY: &str = "txindex";
+const TX_PREFIX: &str = "tx_";
+const HEADER_PREFIX: &str = "header_";
+const PRUNE_KEY: &str = "prune";
+// A pruned node keeps at least this many of the last blocks whole, so it
+// can roll back that deep
+const MIN_BLOCKS_TO_KEEP: i32 = 10;
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
+// How much of the chain a pruned node keeps: the last N blocks, or the last
+// blocks whose bodies fit in a budget of bytes
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum PruneTarget {
+    Depth(i32),
+    Size(u64),
+}
+
+pub enum DataCarrierLookup {
+    Found(Block),
+    NotFound,
+    // Not in the blocks kept, bodies up to this height were pruned
+    Pruned(i32),
+}
+
 pub struct Blockchain {
-    store: KV<String, StoreValue>,
+    store: Store<StoreValue>,
     tip: String,
+    tx_index: bool,
+    prune: Option<(PruneTarget, i32)>,
 }
 
 impl Blockchain {
-    fn exists(store: &mut KV<String, StoreValue>) -> bool {
+    fn exists(store: &Store<StoreValue>) -> bool {
         match store
-            .get(&CHECK_KEY.to_string())
+            .get(CHECK_KEY)
             .expect("error while extracting check data from store")
         {
             Some(_) => true,
@@ -33,17 +70,17 @@ impl Blockchain {
         }
     }
 
-    pub fn new(node_id: &str) -> Blockchain {
-        let db_file = Box::leak(Box::new(format!("blockchain_{}.db", node_id)));
-        let mut store =
-            KV::<String, StoreValue>::new(db_file).expect("error opening blockchain store");
+    pub fn new(data_dir: &DataDir) -> Blockchain {
+        Blockchain::load(Store::open("blockchain", data_dir))
+    }
 
-        if !Blockchain::exists(&mut store) {
+    fn load(store: Store<StoreValue>) -> Blockchain {
+        if !Blockchain::exists(&store) {
             panic!("no existsing blockchain found")
         }
 
         let tip = match store
-            .get(&TIP_KEY.to_string())
+            .get(TIP_KEY)
             .expect("error while extracting tip data from store")
         {
             Some(o) => match o {
@@ -53,173 +90,652 @@ impl Blockchain {
             None => panic!("tip data in store was corrupted"),
         };
 
-        Blockchain { store, tip }
+        let tx_index = store
+            .get(TX_INDEX_KEY)
+            .expect("error while extracting tx index flag from store")
+            .is_some();
+
+        let prune = match store
+            .get(PRUNE_KEY)
+            .expect("error while extracting prune state from store")
+        {
+            Some(o) => match o {
+                StoreValue::Prune(target, height) => Some((target, height)),
+                _ => panic!("wrong type returned from store, StoreValue::Prune was expected"),
+            },
+            None => None,
+        };
+
+        let mut bc = Blockchain {
+            store,
+            tip,
+            tx_index,
+            prune,
+        };
+
+        // Chains stored before headers were kept apart from blocks get them here
+        if !bc.has_header(&bc.tip.clone()) {
+            bc.build_headers();
+        }
+
+        let tip_header = bc.get_header(&bc.tip.clone());
+
+        // Chains stored before the height index existed get it built here
+        if bc.get_block_hash(tip_header.height()).as_deref() != Some(bc.tip()) {
+            let changes = bc.tip_changes(&tip_header);
+            match bc.store.write_batch(changes) {
+                Ok(_) => (),
+                Err(err) => panic!("error while putting height index into store: {}", err),
+            };
+        }
+
+        bc
     }
 
-    pub fn create(node_id: &str, address: &str) -> Blockchain {
-        let db_file = Box::leak(Box::new(format!("blockchain_{}.db", node_id)));
-        let mut store = KV::<String, StoreValue>::new(db_file).expect("error opening store");
+    pub fn create(data_dir: &DataDir, address: &str) -> Blockchain {
+        let store = Store::open("blockchain", data_dir);
 
-        if Blockchain::exists(&mut store) {
+        if Blockchain::exists(&store) {
             panic!("blockchain already exists")
         }
 
-        match store.insert(
-            CHECK_KEY.to_string(),
-            StoreValue::String(CHECK_VALUE.to_string()),
-        ) {
-            Ok(_) => (),
-            Err(err) => panic!("error while putting check data into store: {}", err),
-        };
         let cbtx = Transaction::new_coin_base_tx(address, GENESIS_COINBASE_DATA);
-        let genesis = Block::new_genesis_block(cbtx);
-        match store.insert(
-            genesis.hash().to_string(),
-            StoreValue::Block(genesis.serialize()),
-        ) {
-            Ok(_) => (),
-            Err(err) => panic!("error while putting block data into store: {}", err),
-        };
-        match store.insert(
-            TIP_KEY.to_string(),
-            StoreValue::String(genesis.hash().to_string()),
-        ) {
+        let commitment = utxo_set::genesis_commitment(&cbtx);
+        let genesis = Block::new_genesis_block(cbtx, &commitment);
+        Blockchain::init(store, &genesis)
+    }
+
+    // Starts a chain from a genesis block made elsewhere, e.g. read from a bootstrap file
+    pub fn create_from_genesis(data_dir: &DataDir, genesis: &Block) -> Blockchain {
+        let store = Store::open("blockchain", data_dir);
+
+        if Blockchain::exists(&store) {
+            panic!("blockchain already exists")
+        }
+
+        if let Err(err) = Blockchain::validate_genesis(genesis) {
+            panic!("ERROR: Invalid genesis block: {}", err);
+        }
+
+        Blockchain::init(store, genesis)
+    }
+
+    pub fn exists_in(data_dir: &DataDir) -> bool {
+        Blockchain::exists(&Store::open("blockchain", data_dir))
+    }
+
+    fn init(mut store: Store<StoreValue>, genesis: &Block) -> Blockchain {
+        match store.write_batch(vec![
+            (
+                genesis.hash().to_string(),
+                Some(StoreValue::Block(genesis.serialize())),
+            ),
+            (
+                header_key(genesis.hash()),
+                Some(StoreValue::Header(genesis.header().serialize())),
+            ),
+            (
+                TIP_KEY.to_string(),
+                Some(StoreValue::String(genesis.hash().to_string())),
+            ),
+            (
+                height_key(0),
+                Some(StoreValue::String(genesis.hash().to_string())),
+            ),
+            (
+                CHECK_KEY.to_string(),
+                Some(StoreValue::String(CHECK_VALUE.to_string())),
+            ),
+        ]) {
             Ok(_) => (),
-            Err(err) => panic!("error while putting tip data into store: {}", err),
+            Err(err) => panic!("error while putting genesis block into store: {}", err),
         };
 
         let tip = genesis.hash().to_string();
-        Blockchain { store, tip }
+        Blockchain {
+            store,
+            tip,
+            tx_index: false,
+            prune: None,
+        }
+    }
+
+    fn build_headers(&mut self) {
+        let changes: Vec<(String, Option<StoreValue>)> = self
+            .store
+            .scan_prefix("")
+            .expect("error while scanning blocks in store")
+            .into_iter()
+            .filter_map(|(_, value)| match value {
+                StoreValue::Block(bytes) => {
+                    let header = Block::deserialize(bytes).header();
+                    Some((
+                        header_key(header.hash()),
+                        Some(StoreValue::Header(header.serialize())),
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+
+        match self.store.write_batch(changes) {
+            Ok(_) => (),
+            Err(err) => panic!("error while putting block headers into store: {}", err),
+        };
     }
 
+    // The block, the new tip and its height index entries are written together
     pub fn add_block(&mut self, block: &Block) {
-        match self.store.insert(
+        let header = block.header();
+        let mut changes = self.tip_changes(&header);
+        changes.push((
             block.hash().to_string(),
-            StoreValue::Block(block.serialize()),
-        ) {
-            Ok(_) => (),
-            Err(err) => panic!("error while putting new block into store {}", err),
+            Some(StoreValue::Block(block.serialize())),
+        ));
+        changes.push((
+            header_key(block.hash()),
+            Some(StoreValue::Header(header.serialize())),
+        ));
+
+        if self.tx_index {
+            changes.extend(Blockchain::tx_index_changes(block));
         }
-        match self.store.insert(
-            TIP_KEY.to_string(),
-            StoreValue::String(block.hash().to_string()),
-        ) {
+
+        self.commit(changes);
+        self.tip = block.hash().to_string();
+    }
+
+    // Stores the body of a block only the header of which is known, e.g. one
+    // below a loaded UTXO snapshot
+    pub fn add_block_body(&mut self, block: &Block) -> Result<(), ValidationError> {
+        let block_hash = block.hash().to_string();
+
+        if !ProofOfWork::new(block).validate() {
+            return Err(ValidationError::ProofOfWork { block_hash });
+        }
+
+        if block.header() != self.get_header(&block_hash) {
+            return Err(ValidationError::HeaderMismatch { block_hash });
+        }
+
+        let mut changes = vec![(block_hash, Some(StoreValue::Block(block.serialize())))];
+
+        if self.tx_index {
+            changes.extend(Blockchain::tx_index_changes(block));
+        }
+
+        self.commit(changes);
+        Ok(())
+    }
+
+    // Takes the headers of a chain from genesis up, e.g. the ones a UTXO
+    // snapshot comes with. Each has to link to the one before it and carry
+    // valid proof of work, the first one that doesn't rejects them all. They
+    // have to agree with the active chain, if they go past its tip the last one
+    // becomes the tip while the bodies are missing.
+    pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<(), String> {
+        let mut prev_hash = "";
+
+        for (height, header) in headers.iter().enumerate() {
+            if header.height() != height as i32 || header.prev_block_hash() != prev_hash {
+                return Err(format!(
+                    "header {} doesn't follow its parent",
+                    header.hash()
+                ));
+            }
+
+            if !ProofOfWork::for_header(header).validate() {
+                return Err(format!(
+                    "header {} has no valid proof of work",
+                    header.hash()
+                ));
+            }
+
+            prev_hash = header.hash();
+        }
+
+        let last = match headers.last() {
+            Some(last) => last,
+            None => return Err(String::from("there are no headers")),
+        };
+        let best_height = self.get_best_height();
+        let common = &headers[last.height().min(best_height) as usize];
+
+        if self.get_block_hash(common.height()).as_deref() != Some(common.hash()) {
+            return Err(format!(
+                "header {} at height {} is not on the active chain",
+                common.hash(),
+                common.height()
+            ));
+        }
+
+        if last.height() <= best_height {
+            return Ok(());
+        }
+
+        let changes = headers[best_height as usize + 1..]
+            .iter()
+            .map(|header| {
+                (
+                    header_key(header.hash()),
+                    Some(StoreValue::Header(header.serialize())),
+                )
+            })
+            .collect();
+        self.commit(changes);
+        self.set_tip(last.hash());
+        Ok(())
+    }
+
+    pub fn tip(&self) -> &str {
+        &self.tip[..]
+    }
+
+    pub fn set_tip(&mut self, block_hash: &str) {
+        let header = self.get_header(block_hash);
+        let changes = self.tip_changes(&header);
+        self.commit(changes);
+        self.tip = block_hash.to_string();
+    }
+
+    // Tip changes are on disk before the UTXO set sees them, so after a crash
+    // the set can only lag behind the chain and UTXOSet::recover catches it up
+    fn commit(&mut self, changes: Vec<(String, Option<StoreValue>)>) {
+        match self
+            .store
+            .write_batch(changes)
+            .and_then(|_| self.store.flush())
+        {
             Ok(_) => (),
-            Err(err) => panic!("error while putting tip data into store {}", err),
+            Err(err) => panic!("error while committing new tip to store: {}", err),
         };
-        self.tip = block.hash().to_string();
+    }
+
+    pub fn is_on_active_chain(&mut self, block_hash: &str) -> bool {
+        if !self.has_header(block_hash) {
+            return false;
+        }
+
+        let height = self.get_header(block_hash).height();
+        self.get_block_hash(height).as_deref() == Some(block_hash)
+    }
+
+    // Store changes that make tip the tip: the height index is rewritten from
+    // tip back to where it agrees with the active chain, heights above tip go away
+    fn tip_changes(&mut self, tip: &BlockHeader) -> Vec<(String, Option<StoreValue>)> {
+        let mut changes = vec![(
+            TIP_KEY.to_string(),
+            Some(StoreValue::String(tip.hash().to_string())),
+        )];
+        let best_height = self.get_best_height();
+
+        for height in tip.height() + 1..=best_height {
+            changes.push((height_key(height), None));
+        }
+
+        let mut hash = tip.hash().to_string();
+        let mut prev_hash = tip.prev_block_hash().to_string();
+        let mut height = tip.height();
+
+        while self.get_block_hash(height).as_ref() != Some(&hash) {
+            changes.push((height_key(height), Some(StoreValue::String(hash))));
+
+            if prev_hash.is_empty() {
+                break;
+            }
+
+            hash = prev_hash;
+            prev_hash = self.get_header(&hash).prev_block_hash().to_string();
+            height -= 1;
+        }
+
+        changes
+    }
+
+    // Hash of the block at height on the active chain
+    pub fn get_block_hash(&mut self, height: i32) -> Option<String> {
+        match self
+            .store
+            .get(&height_key(height))
+            .expect("error while extracting height index from store")
+        {
+            Some(o) => match o {
+                StoreValue::String(hash) => Some(hash),
+                _ => panic!("wrong type returned from store, StoreValue::String was expected"),
+            },
+            None => None,
+        }
+    }
+
+    pub fn get_block_by_height(&mut self, height: i32) -> Option<Block> {
+        self.get_block_hash(height)
+            .map(|block_hash| self.get_block(&block_hash))
     }
 
     pub fn get_best_height(&mut self) -> i32 {
+        let tip = self.tip.clone();
+        self.get_header(&tip).height()
+    }
+
+    pub fn has_header(&mut self, block_hash: &str) -> bool {
+        self.store
+            .get(&header_key(block_hash))
+            .expect("error while extracting BlockHeader from store")
+            .is_some()
+    }
+
+    pub fn get_header(&mut self, block_hash: &str) -> BlockHeader {
         match self
             .store
-            .get(&self.tip)
-            .expect("error while extracting Block from store")
+            .get(&header_key(block_hash))
+            .expect("error while extracting BlockHeader from store")
         {
             Some(o) => match o {
-                StoreValue::Block(bytes) => Block::deserialize(bytes).height(),
-                _ => panic!("wrong type returned from store, StoreValue::Block was expected"),
+                StoreValue::Header(bytes) => BlockHeader::deserialize(bytes),
+                _ => panic!("wrong type returned from store, StoreValue::Header was expected"),
             },
-            None => panic!("error, tip was corrupted"),
+            None => panic!("error, block header was not found"),
         }
     }
 
+    pub fn has_block(&mut self, block_hash: &str) -> bool {
+        self.store
+            .get(block_hash)
+            .expect("error while extracting Block from store")
+            .is_some()
+    }
+
     pub fn get_block(&mut self, block_hash: &str) -> Block {
         match self
             .store
-            .get(&block_hash.to_string())
+            .get(block_hash)
             .expect("error while extracting Block from store")
         {
             Some(o) => match o {
                 StoreValue::Block(bytes) => Block::deserialize(bytes),
                 _ => panic!("wrong type returned from store, StoreValue::Block was expected"),
             },
+            None if self.has_header(block_hash) => {
+                panic!("ERROR: Block {} was pruned", block_hash)
+            }
             None => panic!("error, block was not found"),
         }
     }
 
+    // Hashes of the active chain from the tip down, pruned blocks included
     pub fn get_block_hashes(&mut self) -> Vec<String> {
         let mut block_hashes = Vec::new();
+        let mut hash = self.tip.clone();
 
-        for block in self.iter() {
-            block_hashes.push(block.hash().to_string());
+        while !hash.is_empty() {
+            let prev_hash = self.get_header(&hash).prev_block_hash().to_string();
+            block_hashes.push(hash);
+            hash = prev_hash;
         }
 
         block_hashes
     }
 
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Block {
-        for tx in &transactions {
-            if !self.verify_transaction(tx) {
-                panic!("ERROR: Invalid transaction");
+    // Height up to which block bodies are pruned, None on a node that
+    // keeps the whole chain
+    pub fn pruned_height(&self) -> Option<i32> {
+        self.prune
+            .map(|(_, height)| height)
+            .filter(|height| *height >= 0)
+    }
+
+    pub fn set_prune_target(&mut self, target: PruneTarget) {
+        if self.tx_index {
+            panic!("ERROR: The transaction index needs every block, it can't be used with pruning");
+        }
+
+        if let PruneTarget::Depth(depth) = target {
+            if depth < MIN_BLOCKS_TO_KEEP {
+                panic!(
+                    "ERROR: A pruned node keeps at least {} blocks",
+                    MIN_BLOCKS_TO_KEEP
+                );
             }
         }
 
+        let height = self.prune.map_or(-1, |(_, height)| height);
+        self.commit(vec![(
+            PRUNE_KEY.to_string(),
+            Some(StoreValue::Prune(target, height)),
+        )]);
+        self.prune = Some((target, height));
+    }
+
+    // Deletes bodies of the active chain blocks the prune target doesn't keep
+    // and their undo data. Returns the number of pruned blocks.
+    pub fn prune(&mut self, utxo_set: &mut UTXOSet) -> usize {
+        let block_hashes = self.prune_blocks(utxo_set);
+        utxo_set.prune_undo(&block_hashes);
+        block_hashes.len()
+    }
+
+    // The UTXO set is flushed first and no body above its best block goes, so
+    // a crash before prune_undo leaves a set recover can catch up without them
+    fn prune_blocks(&mut self, utxo_set: &mut UTXOSet) -> Vec<String> {
+        let (target, pruned_height) = match self.prune {
+            Some(prune) => prune,
+            None => return Vec::new(),
+        };
+
+        // Validating a loaded snapshot needs the bodies below it
+        if utxo_set.pending_snapshot().is_some() {
+            return Vec::new();
+        }
+
+        utxo_set.flush();
+        let utxo_height = match utxo_set.best_block() {
+            Some(block_hash) if self.is_on_active_chain(&block_hash) => {
+                self.get_header(&block_hash).height()
+            }
+            _ => return Vec::new(),
+        };
+        let best_height = self.get_best_height();
+        let mut keep_from = (best_height - MIN_BLOCKS_TO_KEEP).min(utxo_height) + 1;
+
+        match target {
+            PruneTarget::Depth(depth) => keep_from = keep_from.min(best_height - depth + 1),
+            PruneTarget::Size(budget) => {
+                let mut size = 0;
+                let mut height = best_height;
+
+                while height > pruned_height {
+                    size += match self.get_block_hash(height) {
+                        Some(hash) => self.block_size(&hash),
+                        None => break,
+                    };
+
+                    if size > budget {
+                        break;
+                    }
+
+                    height -= 1;
+                }
+
+                keep_from = keep_from.min(height + 1);
+            }
+        }
+
+        if keep_from <= pruned_height + 1 {
+            return Vec::new();
+        }
+
+        let mut changes = Vec::new();
+        let mut block_hashes = Vec::new();
+
+        for height in pruned_height + 1..keep_from {
+            if let Some(hash) = self.get_block_hash(height) {
+                changes.push((hash.clone(), None));
+                block_hashes.push(hash);
+            }
+        }
+
+        let height = keep_from - 1;
+        changes.push((
+            PRUNE_KEY.to_string(),
+            Some(StoreValue::Prune(target, height)),
+        ));
+        self.commit(changes);
+        self.prune = Some((target, height));
+        block_hashes
+    }
+
+    fn block_size(&mut self, block_hash: &str) -> u64 {
+        self.get_block(block_hash).serialize().len() as u64
+    }
+
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>, utxo_set: &mut UTXOSet) -> Block {
         let height = self.get_best_height();
-        let new_block = Block::new(transactions, &self.tip[..], height + 1);
-        match self.store.insert(
-            new_block.hash().to_string(),
-            StoreValue::Block(new_block.serialize()),
-        ) {
-            Ok(_) => (),
-            Err(err) => panic!("error while putting new block into store {}", err),
+
+        if let Err(err) = self.validate_transactions(&transactions, utxo_set) {
+            panic!("ERROR: Invalid block: {}", err);
         }
-        match self.store.insert(
-            TIP_KEY.to_string(),
-            StoreValue::String(new_block.hash().to_string()),
-        ) {
-            Ok(_) => (),
-            Err(err) => panic!("error while putting tip data into store {}", err),
+
+        for tx in &transactions {
+            if !tx.is_final(height + 1) {
+                panic!("ERROR: Transaction {} is not final yet", tx.id());
+            }
+        }
+
+        if let Err(reason) = Blockchain::check_coinbase(&transactions) {
+            panic!("ERROR: Invalid block: {}", reason);
+        }
+
+        let commitment = match utxo_set.next_commitment(&transactions, height + 1) {
+            Ok(commitment) => commitment,
+            Err(err) => panic!("ERROR: Invalid block: {}", err),
         };
-        self.tip = new_block.hash().to_string();
+        let new_block = Block::new(transactions, &self.tip[..], height + 1, &commitment);
+        self.add_block(&new_block);
         new_block
     }
 
     pub fn iter<'a>(&'a mut self) -> BlockchainIterator<'a> {
         BlockchainIterator {
-            store: &mut self.store,
+            store: &self.store,
             tip: self.tip.clone(),
         }
     }
 
-    pub fn find_utxo(&mut self) -> HashMap<String, TXOutputs> {
-        let mut utxo: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
-
+    pub fn find_data_carrier(&mut self, data: &[u8]) -> DataCarrierLookup {
         for block in self.iter() {
             for tx in block.transactions() {
-                'outputs: for (idx, out_tx) in tx.v_out().iter().enumerate() {
-                    if spent_txos.contains_key(tx.id()) {
-                        for spent_out in spent_txos[tx.id()].iter() {
-                            if *spent_out == idx as i32 {
-                                continue 'outputs;
-                            }
-                        }
+                for out in tx.v_out() {
+                    if out.is_data_carrier() && out.data() == data {
+                        return DataCarrierLookup::Found(block);
                     }
-
-                    let outs = utxo
-                        .entry(tx.id().to_string())
-                        .or_insert(TXOutputs::new(Vec::new()));
-                    outs.outputs.push(out_tx.clone());
-                    let tmp = outs.clone();
-                    utxo.insert(tx.id().to_string(), tmp);
                 }
+            }
+        }
 
-                if !tx.is_coinbase() {
-                    for in_tx in tx.v_in() {
-                        spent_txos
-                            .entry(in_tx.tx_id().to_string())
-                            .or_insert(Vec::new())
-                            .push(in_tx.v_out());
-                    }
-                }
+        // The walk ends at the first pruned block, what lies below is unknown
+        match self.pruned_height() {
+            Some(height) => DataCarrierLookup::Pruned(height),
+            None => DataCarrierLookup::NotFound,
+        }
+    }
+
+    fn tx_index_changes(block: &Block) -> Vec<(String, Option<StoreValue>)> {
+        block
+            .transactions()
+            .iter()
+            .enumerate()
+            .map(|(position, tx)| {
+                (
+                    tx_key(tx.id()),
+                    Some(StoreValue::TxLocation(block.hash().to_string(), position)),
+                )
+            })
+            .collect()
+    }
+
+    pub fn has_tx_index(&self) -> bool {
+        self.tx_index
+    }
+
+    // Drops the transaction index and builds it again from the active chain,
+    // enabling it if it wasn't. Returns the number of indexed transactions.
+    pub fn reindex_transactions(&mut self) -> usize {
+        if self.prune.is_some() {
+            panic!("ERROR: The transaction index needs every block, it can't be used with pruning");
+        }
+
+        let mut changes: Vec<(String, Option<StoreValue>)> = self
+            .store
+            .scan_prefix(TX_PREFIX)
+            .expect("error while scanning tx index in store")
+            .into_iter()
+            .map(|(key, _)| (key, None))
+            .collect();
+        let mut count = 0;
+
+        for block in self.iter() {
+            count += block.transactions().len();
+            changes.extend(Blockchain::tx_index_changes(&block));
+        }
+
+        changes.push((
+            TX_INDEX_KEY.to_string(),
+            Some(StoreValue::String(String::new())),
+        ));
+
+        match self.store.write_batch(changes) {
+            Ok(_) => (),
+            Err(err) => panic!("error while putting tx index into store: {}", err),
+        };
+        self.tx_index = true;
+        count
+    }
+
+    // Transaction with the given id on the active chain and the block holding it,
+    // looked up through the transaction index
+    pub fn get_transaction(&mut self, id: &str) -> Option<(Transaction, Block)> {
+        let (block_hash, position) = match self
+            .store
+            .get(&tx_key(id))
+            .expect("error while extracting tx index from store")
+        {
+            Some(o) => match o {
+                StoreValue::TxLocation(block_hash, position) => (block_hash, position),
+                _ => panic!("wrong type returned from store, StoreValue::TxLocation was expected"),
+            },
+            None => return None,
+        };
+        // Blocks that were disconnected keep their entries until overwritten
+        if !self.is_on_active_chain(&block_hash) {
+            return None;
+        }
+
+        let block = self.get_block(&block_hash);
+
+        let tx = block.transactions()[position].clone();
+        Some((tx, block))
+    }
+
+    // Transaction an input spends. Once its block is pruned, or not downloaded
+    // yet below a loaded snapshot, only the outputs still in the UTXO set are
+    // known, which is all signing and verifying need.
+    fn find_spent_transaction(&mut self, id: &str, utxo_set: &mut UTXOSet) -> Transaction {
+        if self.prune.is_some() || utxo_set.pending_snapshot().is_some() {
+            if let Some(tx) = utxo_set.unspent_transaction(id) {
+                return tx;
             }
         }
 
-        utxo
+        self.find_transaction(id)
     }
 
     pub fn find_transaction(&mut self, id: &str) -> Transaction {
+        if self.tx_index {
+            if let Some((tx, _)) = self.get_transaction(id) {
+                return tx;
+            }
+        }
+
         for block in self.iter() {
             for tx in block.transactions() {
                 if tx.id() == id {
@@ -231,35 +747,290 @@ impl Blockchain {
         panic!("Transaction is not found");
     }
 
-    pub fn sign_transaction(&mut self, tx: &mut Transaction, pkcs8_bytes: &[u8]) {
+    pub fn sign_transaction(
+        &mut self,
+        tx: &mut Transaction,
+        pkcs8_bytes: &[u8],
+        utxo_set: &mut UTXOSet,
+    ) {
         let mut prev_txs = HashMap::new();
 
         for tx_in in tx.v_in() {
-            let prev_tx = self.find_transaction(tx_in.tx_id());
+            let prev_tx = self.find_spent_transaction(tx_in.tx_id(), utxo_set);
             prev_txs.insert(prev_tx.id().to_string(), prev_tx);
         }
 
         tx.sign(pkcs8_bytes, &prev_txs);
     }
 
-    pub fn verify_transaction(&mut self, tx: &Transaction) -> bool {
-        if tx.is_coinbase() {
-            return true;
+    pub fn sign_policy_transaction(
+        &mut self,
+        tx: &mut Transaction,
+        satisfier: &Satisfier,
+        utxo_set: &mut UTXOSet,
+    ) {
+        for idx in 0..tx.v_in().len() {
+            let prev_tx = self.find_spent_transaction(tx.v_in()[idx].tx_id(), utxo_set);
+            tx.sign_policy_input(idx, &prev_tx, satisfier, transaction::SIGHASH_ALL);
         }
+    }
 
-        let mut prev_txs = HashMap::new();
+    // A genesis block holds just its coinbase and commits to the set it creates
+    fn validate_genesis(genesis: &Block) -> Result<(), ValidationError> {
+        let block_hash = genesis.hash().to_string();
 
-        for tx_in in tx.v_in() {
-            let prev_tx = self.find_transaction(tx_in.tx_id());
-            prev_txs.insert(prev_tx.id().to_string(), prev_tx);
+        if !genesis.prev_block_hash().is_empty() || genesis.height() != 0 {
+            return Err(ValidationError::InvalidGenesis { block_hash });
+        }
+
+        if !ProofOfWork::new(genesis).validate() {
+            return Err(ValidationError::ProofOfWork { block_hash });
+        }
+
+        match genesis.transactions() {
+            [cbtx]
+                if cbtx.verify(&HashMap::new())
+                    && Blockchain::check_coinbase(genesis.transactions()).is_ok() =>
+            {
+                if utxo_set::genesis_commitment(cbtx) != genesis.utxo_commitment() {
+                    return Err(ValidationError::UTXOCommitment { block_hash });
+                }
+
+                Ok(())
+            }
+            _ => Err(ValidationError::InvalidGenesis { block_hash }),
+        }
+    }
+
+    // Checks a block from a peer or a bootstrap file before it goes on top of the tip
+    pub fn validate_block(
+        &mut self,
+        block: &Block,
+        utxo_set: &mut UTXOSet,
+    ) -> Result<(), ValidationError> {
+        let block_hash = block.hash().to_string();
+
+        if block.prev_block_hash() != self.tip() {
+            return Err(ValidationError::NotOnTip { block_hash });
+        }
+
+        if block.height() != self.get_best_height() + 1 {
+            return Err(ValidationError::WrongHeight {
+                block_hash,
+                height: block.height(),
+            });
+        }
+
+        if !ProofOfWork::new(block).validate() {
+            return Err(ValidationError::ProofOfWork { block_hash });
+        }
+
+        for tx in block.transactions() {
+            if !tx.is_final(block.height()) {
+                return Err(ValidationError::NotFinal {
+                    tx_id: tx.id().to_string(),
+                });
+            }
+        }
+
+        if let Err(reason) = Blockchain::check_coinbase(block.transactions()) {
+            return Err(ValidationError::InvalidCoinbase { block_hash, reason });
+        }
+
+        self.validate_transactions(block.transactions(), utxo_set)?;
+
+        // Transactions that passed validation spend outputs the set has, an
+        // error here is taken for a commitment mismatch all the same
+        match utxo_set.next_commitment(block.transactions(), block.height()) {
+            Ok(commitment) if commitment == block.utxo_commitment() => (),
+            _ => return Err(ValidationError::UTXOCommitment { block_hash }),
+        }
+
+        Ok(())
+    }
+
+    // A block starts with its only coinbase, which can't pay out more than the
+    // subsidy. Fees aren't claimed, whatever inputs leave over is burned.
+    fn check_coinbase(transactions: &[Transaction]) -> Result<(), String> {
+        let cbtx = match transactions.first() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Err(String::from("the first transaction is not a coinbase")),
+        };
+
+        if let Some(tx) = transactions[1..].iter().find(|tx| tx.is_coinbase()) {
+            return Err(format!("transaction {} is a second coinbase", tx.id()));
+        }
+
+        let subsidy = Amount::from_coins(transaction::SUBSIDY)
+            .expect("error, subsidy exceeds the supply limit");
+
+        match Amount::checked_sum(cbtx.v_out().iter().map(|out| out.value())) {
+            Some(value) if value <= subsidy => Ok(()),
+            _ => Err(format!("coinbase {} pays more than the subsidy", cbtx.id())),
+        }
+    }
+
+    // Checks the transactions of a block against the UTXO set, outputs created
+    // by earlier transactions in the block can be spent by later ones
+    pub fn validate_transactions(
+        &mut self,
+        transactions: &[Transaction],
+        utxo_set: &mut UTXOSet,
+    ) -> Result<(), ValidationError> {
+        let mut block_txs: HashMap<String, Transaction> = HashMap::new();
+        let mut spent = HashSet::new();
+
+        for tx in transactions {
+            let mut prev_txs = HashMap::new();
+
+            if !tx.is_coinbase() {
+                let mut own = HashSet::new();
+
+                for tx_in in tx.v_in() {
+                    let key = utxo_set::outpoint(tx_in.tx_id(), tx_in.v_out());
+
+                    if !own.insert(key.clone()) {
+                        return Err(ValidationError::DuplicateInput {
+                            tx_id: tx.id().to_string(),
+                            outpoint: key,
+                        });
+                    }
+
+                    if !spent.insert(key.clone()) {
+                        return Err(ValidationError::Conflict {
+                            tx_id: tx.id().to_string(),
+                            outpoint: key,
+                        });
+                    }
+
+                    let created_in_block = match block_txs.get(tx_in.tx_id()) {
+                        Some(prev_tx) => prev_tx
+                            .v_out()
+                            .get(tx_in.v_out() as usize)
+                            .is_some_and(|out| !out.is_data_carrier()),
+                        None => false,
+                    };
+
+                    if !created_in_block && !utxo_set.contains(tx_in.tx_id(), tx_in.v_out()) {
+                        return Err(ValidationError::AlreadySpent {
+                            tx_id: tx.id().to_string(),
+                            outpoint: key,
+                        });
+                    }
+
+                    if !prev_txs.contains_key(tx_in.tx_id()) {
+                        let prev_tx = match block_txs.get(tx_in.tx_id()) {
+                            Some(prev_tx) => prev_tx.clone(),
+                            None => self.find_spent_transaction(tx_in.tx_id(), utxo_set),
+                        };
+                        prev_txs.insert(prev_tx.id().to_string(), prev_tx);
+                    }
+                }
+            }
+
+            if !tx.verify(&prev_txs) {
+                return Err(ValidationError::InvalidTransaction {
+                    tx_id: tx.id().to_string(),
+                });
+            }
+
+            block_txs.insert(tx.id().to_string(), tx.clone());
         }
 
-        tx.verify(&prev_txs)
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    // Two transactions in the same block spend one output
+    Conflict { tx_id: String, outpoint: String },
+    // Two inputs of the same transaction spend one output
+    DuplicateInput { tx_id: String, outpoint: String },
+    // Input spends an output that isn't unspent, either spent by an earlier block or never created
+    AlreadySpent { tx_id: String, outpoint: String },
+    InvalidTransaction { tx_id: String },
+    NotFinal { tx_id: String },
+    NotOnTip { block_hash: String },
+    WrongHeight { block_hash: String, height: i32 },
+    ProofOfWork { block_hash: String },
+    UTXOCommitment { block_hash: String },
+    InvalidGenesis { block_hash: String },
+    InvalidCoinbase { block_hash: String, reason: String },
+    HeaderMismatch { block_hash: String },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::Conflict { tx_id, outpoint } => write!(
+                f,
+                "transaction {} spends {} which another transaction in the block already spends",
+                tx_id, outpoint
+            ),
+            ValidationError::DuplicateInput { tx_id, outpoint } => write!(
+                f,
+                "transaction {} spends {} in more than one input",
+                tx_id, outpoint
+            ),
+            ValidationError::AlreadySpent { tx_id, outpoint } => write!(
+                f,
+                "transaction {} spends {} which is not unspent",
+                tx_id, outpoint
+            ),
+            ValidationError::InvalidTransaction { tx_id } => {
+                write!(f, "transaction {} is invalid", tx_id)
+            }
+            ValidationError::NotFinal { tx_id } => {
+                write!(f, "transaction {} is not final yet", tx_id)
+            }
+            ValidationError::NotOnTip { block_hash } => {
+                write!(f, "block {} doesn't extend the tip", block_hash)
+            }
+            ValidationError::WrongHeight { block_hash, height } => write!(
+                f,
+                "block {} claims height {} which doesn't follow its parent",
+                block_hash, height
+            ),
+            ValidationError::ProofOfWork { block_hash } => {
+                write!(f, "block {} has no valid proof of work", block_hash)
+            }
+            ValidationError::UTXOCommitment { block_hash } => {
+                write!(f, "block {} commits to a different UTXO set", block_hash)
+            }
+            ValidationError::InvalidGenesis { block_hash } => write!(
+                f,
+                "block {} is not a genesis block with a single coinbase",
+                block_hash
+            ),
+            ValidationError::InvalidCoinbase { block_hash, reason } => {
+                write!(
+                    f,
+                    "block {} has an invalid coinbase: {}",
+                    block_hash, reason
+                )
+            }
+            ValidationError::HeaderMismatch { block_hash } => {
+                write!(f, "block {} doesn't match its header", block_hash)
+            }
+        }
     }
 }
 
+fn height_key(height: i32) -> String {
+    format!("{}{}", HEIGHT_PREFIX, height)
+}
+
+fn header_key(block_hash: &str) -> String {
+    format!("{}{}", HEADER_PREFIX, block_hash)
+}
+
+fn tx_key(id: &str) -> String {
+    format!("{}{}", TX_PREFIX, id)
+}
+
 pub struct BlockchainIterator<'a> {
-    store: &'a mut KV<String, StoreValue>,
+    store: &'a Store<StoreValue>,
     tip: String,
 }
 
@@ -284,3 +1055,170 @@ impl<'a> Iterator for BlockchainIterator<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coin_selection::LargestFirst;
+    use crate::storage::MemoryStorage;
+    use crate::wallet::Wallet;
+    use std::mem;
+
+    // A kill after the bodies are deleted but before their undo data is, with
+    // blocks still in the UTXO cache, leaves a set recover brings to the tip
+    #[test]
+    fn recovers_after_crash_while_pruning() {
+        let address = Wallet::new().get_address();
+        let chain = MemoryStorage::new();
+        let utxo = MemoryStorage::new();
+        let cbtx = Transaction::new_coin_base_tx(&address, GENESIS_COINBASE_DATA);
+        let commitment = utxo_set::genesis_commitment(&cbtx);
+        let genesis = Block::new_genesis_block(cbtx, &commitment);
+        let mut bc = Blockchain::init(Store::new(Box::new(chain.clone())), &genesis);
+        let mut utxo_set = UTXOSet::with_storage(Box::new(utxo.clone()));
+        utxo_set.reindex(&mut bc);
+        utxo_set.flush();
+        bc.set_prune_target(PruneTarget::Depth(MIN_BLOCKS_TO_KEEP));
+        let blocks = MIN_BLOCKS_TO_KEEP + 5;
+
+        for _ in 0..blocks {
+            let txs = vec![Transaction::new_coin_base_tx(&address, "")];
+            let block = bc.mine_block(txs, &mut utxo_set);
+            utxo_set.update(&block);
+        }
+
+        assert!(!bc.prune_blocks(&mut utxo_set).is_empty());
+        mem::forget(utxo_set);
+        mem::forget(bc);
+
+        let mut bc = Blockchain::load(Store::new(Box::new(chain)));
+        let mut utxo_set = UTXOSet::with_storage(Box::new(utxo));
+        utxo_set.recover(&mut bc);
+        assert_eq!(utxo_set.best_block().as_deref(), Some(bc.tip()));
+        assert_eq!(utxo_set.info().outputs, blocks as usize + 1);
+        assert_eq!(bc.prune(&mut utxo_set), 0);
+    }
+
+    // A header whose hash isn't the one its content gives rejects the whole
+    // chain, even with the headers before it valid
+    #[test]
+    fn rejects_headers_on_first_bad_proof_of_work() {
+        let address = Wallet::new().get_address();
+        let cbtx = Transaction::new_coin_base_tx(&address, GENESIS_COINBASE_DATA);
+        let commitment = utxo_set::genesis_commitment(&cbtx);
+        let genesis = Block::new_genesis_block(cbtx, &commitment);
+        let mut bc = Blockchain::init(Store::new(Box::new(MemoryStorage::new())), &genesis);
+        let mut utxo_set = UTXOSet::with_storage(Box::new(MemoryStorage::new()));
+        utxo_set.reindex(&mut bc);
+        let mut headers = vec![genesis.header()];
+
+        for _ in 0..3 {
+            let txs = vec![Transaction::new_coin_base_tx(&address, "")];
+            let block = bc.mine_block(txs, &mut utxo_set);
+            utxo_set.update(&block);
+            headers.push(block.header());
+        }
+
+        let mut tampered = headers.clone();
+        let mut bytes = tampered[2].serialize();
+        bytes[0] ^= 1;
+        tampered[2] = BlockHeader::deserialize(bytes);
+
+        let mut other = Blockchain::init(Store::new(Box::new(MemoryStorage::new())), &genesis);
+        assert!(other.add_headers(&tampered).is_err());
+        assert_eq!(other.tip(), genesis.hash());
+        assert!(!other.has_header(headers[1].hash()));
+
+        assert!(other.add_headers(&headers).is_ok());
+        assert_eq!(other.tip(), bc.tip());
+    }
+
+    // A chain in memory whose genesis block pays the wallet, with its UTXO set
+    fn funded_chain(wallet: &Wallet) -> (Blockchain, UTXOSet) {
+        let cbtx = Transaction::new_coin_base_tx(&wallet.get_address(), GENESIS_COINBASE_DATA);
+        let commitment = utxo_set::genesis_commitment(&cbtx);
+        let genesis = Block::new_genesis_block(cbtx, &commitment);
+        let mut bc = Blockchain::init(Store::new(Box::new(MemoryStorage::new())), &genesis);
+        let mut utxo_set = UTXOSet::with_storage(Box::new(MemoryStorage::new()));
+        utxo_set.reindex(&mut bc);
+        (bc, utxo_set)
+    }
+
+    fn coins(coins: u64) -> Amount {
+        Amount::from_coins(coins).unwrap()
+    }
+
+    #[test]
+    fn rejects_block_with_conflicting_transactions() {
+        let wallet = Wallet::new();
+        let to = Wallet::new().get_address();
+        let (mut bc, mut utxo_set) = funded_chain(&wallet);
+        let first = Transaction::new_utxo_tx(
+            &wallet,
+            &to,
+            coins(1),
+            &mut bc,
+            &mut utxo_set,
+            &LargestFirst,
+        );
+        let second = Transaction::new_utxo_tx(
+            &wallet,
+            &to,
+            coins(2),
+            &mut bc,
+            &mut utxo_set,
+            &LargestFirst,
+        );
+        assert_eq!(first.v_in()[0].tx_id(), second.v_in()[0].tx_id());
+
+        let cbtx = Transaction::new_coin_base_tx(&wallet.get_address(), "");
+        let block = Block::new(vec![cbtx, first, second.clone()], bc.tip(), 1, "");
+
+        match bc.validate_block(&block, &mut utxo_set) {
+            Err(ValidationError::Conflict { tx_id, .. }) => assert_eq!(tx_id, second.id()),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_block_with_misplaced_or_overpaying_coinbase() {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let (mut bc, mut utxo_set) = funded_chain(&wallet);
+        let cbtx = Transaction::new_coin_base_tx(&address, "");
+        let tx = Transaction::new_utxo_tx(
+            &wallet,
+            &address,
+            coins(1),
+            &mut bc,
+            &mut utxo_set,
+            &LargestFirst,
+        );
+
+        // Same coinbase paying one coin more than the subsidy
+        let mut bytes = cbtx.serialize();
+        let subsidy = coins(transaction::SUBSIDY).base_units().to_le_bytes();
+        let more = coins(transaction::SUBSIDY + 1).base_units().to_le_bytes();
+        let at = bytes.windows(8).position(|w| w == subsidy).unwrap();
+        bytes[at..at + 8].copy_from_slice(&more);
+        let overpaying = Transaction::deserialize(bytes);
+
+        let second = Transaction::new_coin_base_tx(&address, "");
+        let blocks = [
+            (vec![tx.clone(), cbtx.clone()], "not a coinbase"),
+            (vec![cbtx, second], "second coinbase"),
+            (vec![overpaying, tx], "more than the subsidy"),
+        ];
+
+        for (txs, expected) in blocks.iter() {
+            let block = Block::new(txs.clone(), bc.tip(), 1, "");
+
+            match bc.validate_block(&block, &mut utxo_set) {
+                Err(ValidationError::InvalidCoinbase { reason, .. }) => {
+                    assert!(reason.contains(expected), "{}", reason)
+                }
+                other => panic!("expected an invalid coinbase, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use crate::block::Block;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+// A bootstrap file is the magic and a version byte followed by the blocks
+// from genesis up, one frame each: the block length as u32 LE, the first
+// bytes of its SHA-256 and the serialized block
+const MAGIC: &[u8; 4] = b"BCRB";
+const VERSION: u8 = 1;
+const CHECKSUM_SIZE: usize = 4;
+// Longer frames are taken for corruption instead of being allocated
+const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = Sha256::new();
+    let mut digest: [u8; 32] = [0; 32];
+    hasher.input(bytes);
+    hasher.result(&mut digest);
+
+    let mut checksum = [0; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&digest[..CHECKSUM_SIZE]);
+    checksum
+}
+
+pub struct BootstrapWriter {
+    file: BufWriter<File>,
+    path: String,
+}
+
+impl BootstrapWriter {
+    pub fn create(path: &str) -> BootstrapWriter {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => panic!("error creating file {}: {}", path, err),
+        };
+        let mut writer = BootstrapWriter {
+            file: BufWriter::new(file),
+            path: path.to_string(),
+        };
+
+        writer.write(MAGIC);
+        writer.write(&[VERSION]);
+        writer
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self.file.write_all(bytes) {
+            Ok(_) => (),
+            Err(err) => panic!("error writing file {}: {}", self.path, err),
+        };
+    }
+
+    pub fn write_block(&mut self, block: &Block) {
+        let bytes = block.serialize();
+
+        self.write(&(bytes.len() as u32).to_le_bytes());
+        self.write(&checksum(&bytes));
+        self.write(&bytes);
+    }
+
+    pub fn finish(mut self) {
+        match self
+            .file
+            .flush()
+            .and_then(|_| self.file.get_ref().sync_all())
+        {
+            Ok(_) => (),
+            Err(err) => panic!("error writing file {}: {}", self.path, err),
+        };
+    }
+}
+
+// Yields the blocks of a bootstrap file in order, a damaged frame is an error
+pub struct BootstrapReader {
+    file: BufReader<File>,
+    path: String,
+    frame: usize,
+}
+
+impl BootstrapReader {
+    pub fn open(path: &str) -> BootstrapReader {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => panic!("error reading file {}: {}", path, err),
+        };
+        let mut reader = BootstrapReader {
+            file: BufReader::new(file),
+            path: path.to_string(),
+            frame: 0,
+        };
+        let mut magic = [0; 4];
+        let mut version = [0; 1];
+
+        if reader.file.read_exact(&mut magic).is_err() || magic != *MAGIC {
+            panic!("ERROR: {} is not a bootstrap file", path);
+        }
+
+        reader.read(&mut version);
+
+        if version[0] != VERSION {
+            panic!(
+                "ERROR: {} has bootstrap format version {}, only {} is supported",
+                path, version[0], VERSION
+            );
+        }
+
+        reader
+    }
+
+    fn read(&mut self, buf: &mut [u8]) {
+        match self.file.read_exact(buf) {
+            Ok(_) => (),
+            Err(err) => panic!(
+                "ERROR: {} is truncated in frame {}: {}",
+                self.path, self.frame, err
+            ),
+        };
+    }
+}
+
+impl Iterator for BootstrapReader {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The file may only end between frames
+        match self.file.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => (),
+            Err(err) => panic!("error reading file {}: {}", self.path, err),
+        };
+
+        let mut len = [0; 4];
+        let mut expected_checksum = [0; CHECKSUM_SIZE];
+        self.read(&mut len);
+        self.read(&mut expected_checksum);
+
+        let len = u32::from_le_bytes(len) as usize;
+
+        if len > MAX_FRAME_SIZE {
+            panic!(
+                "ERROR: Frame {} of {} is {} bytes long, the file is corrupted",
+                self.frame, self.path, len
+            );
+        }
+
+        let mut bytes = vec![0; len];
+        self.read(&mut bytes);
+
+        if checksum(&bytes) != expected_checksum {
+            panic!(
+                "ERROR: Frame {} of {} doesn't match its checksum, the file is corrupted",
+                self.frame, self.path
+            );
+        }
+
+        self.frame += 1;
+        Some(Block::deserialize(bytes))
+    }
+}
@@ -1,11 +1,21 @@
-use crate::blockchain::Blockchain;
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, DataCarrierLookup, PruneTarget};
+use crate::bootstrap::{BootstrapReader, BootstrapWriter};
+use crate::coin_selection::{self, CoinSelector, ManualSelection};
+use crate::datadir::{self, DataDir};
+use crate::policy::{Policy, Satisfier};
 use crate::proofofwork::ProofOfWork;
 use crate::transaction::Transaction;
-use crate::utxo_set::UTXOSet;
+use crate::utxo_set::{UTXOSet, UTXOSnapshot};
 use crate::wallet::Wallet;
 use crate::wallets::Wallets;
 use crate::server::{Server, TxWrapper};
 
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::env;
+use std::fs;
 use std::process;
 use std::net::TcpStream;
 use std::io::{Write};
@@ -21,15 +31,107 @@ impl<'a> CLI<'a> {
 
     fn print_usage(&self) {
         println!("Usage:");
+        println!("    compilepolicy -policy POLICY - show the address and satisfaction cost of POLICY");
         println!("    createblockchain -address ADDRESS - create blockchain and send genesis block reward to ADDRESS");
         println!("    createwallet - generates a new key pair abd saves it into the wallet file");
+        println!("    dumputxo -file PATH - write the UTXO set at its best block to PATH");
+        println!("    exportchain -file PATH - write the blocks from genesis to the tip to the bootstrap file PATH");
         println!("    getbalance -address ADDRESS - get balance of ADDRESS");
+        println!("    getblock -height N - print the block at height N of the active chain");
+        println!("    getpubkey -address ADDRESS - print the public key of ADDRESS for use in pk()");
+        println!("    gettransaction -id TXID - print the transaction TXID and the block holding it, needs the transaction index");
+        println!("    gettxoutsetinfo - print statistics of the UTXO set and a hash to compare it with other nodes");
+        println!("    importchain -file PATH - validate the blocks of the bootstrap file PATH and add them to the chain,");
+        println!("                 which starts from the genesis block of the file if there's none yet");
         println!("    listaddresses - lists all addresses from the wallet file");
+        println!("    listunspent -address ADDRESS - list spendable outputs of ADDRESS as TXID:VOUT");
+        println!("    loadutxo -file PATH - replace the UTXO set with the snapshot in PATH, needs only the genesis block, startnode downloads the blocks and validates it");
+        println!("    notarize -file PATH -address ADDRESS - put hash of the file PATH on chain in a transaction paid from ADDRESS, mine on the same node, when -mine is set");
+        println!("    previewsend -from FROM -amount AMOUNT - show inputs and change a send of AMOUNT from FROM would use");
         println!("    printchain - print all the blocks of the blockchain");
-        println!("    reindexutxo - rebuilds the utxo set");
-        println!("    send -from FROM -to TO -amount AMOUNT - send AMOUNT of coins from FROM address to TO;
+        println!("    reindexutxo - rebuilds the utxo set, and the transaction index if it's enabled;
+                 -txindex enables the transaction index");
+        println!("    rollback -blocks N - disconnect the last N blocks and make their parent the tip");
+        println!("    send -from FROM -to TO -amount AMOUNT - send AMOUNT of coins (up to 8 decimals) from FROM address to TO;
                  mine on the same node, when -mine is set");
-        println!("    startnode -miner ADDRESS - start a node with ID specified in NODE_ID env. var. -miner enables mining");
+        println!("    sendmany -from FROM -to TO:AMOUNT,TO:AMOUNT - pay every recipient from FROM in a single transaction;
+                 -file CSV reads ADDRESS,AMOUNT lines instead of -to, mine on the same node, when -mine is set");
+        println!("    spendpolicy -policy POLICY -to TO -amount AMOUNT - spend coins locked with POLICY using keys from the wallet file;
+                 mine on the same node, when -mine is set");
+        println!("    startnode -miner ADDRESS - start a node listening on -port, -miner enables mining;
+                 -prune N keeps only the last N blocks whole, -prunesize MB the last blocks that fit in MB,
+                 both stay in effect for later starts");
+        println!("    verifynotary -file PATH - find the block where hash of the file PATH was notarized");
+        println!("    verifyutxo - rebuild the UTXO set from the chain and list outputs the stored one gets wrong;
+                 fix them, when -repair is set");
+        println!();
+        println!("Options for every command, after the command's own:");
+        println!("    -datadir DIR - keep data in DIR/NETWORK instead of files named after NODE_ID in the working directory");
+        println!("    -network NAME - network subdirectory of -datadir, main by default");
+        println!("    -port PORT - port the node listens on and sends from, NODE_ID env. var. by default");
+        println!();
+        println!("Coin selection for send, sendmany and previewsend:");
+        println!("    -strategy auto|bnb|largest|smallest|random - how inputs are picked, auto tries an exact match first");
+        println!("    -coins TXID:VOUT,TXID:VOUT - spend exactly the given outputs");
+        println!();
+        println!("Policies are built from pk(HEXKEY), after(HEIGHT), and(P,P,...), or(P,P,...) and thresh(K,P,P,...)");
+        println!();
+        println!("UTXO_CACHE_MB env. var. sets the memory budget of the UTXO cache, 32 by default");
+        println!("STORAGE env. var. picks the storage backend: sled (default) or memory, which keeps nothing after exit");
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        let idx = self.args.iter().position(|arg| arg == name)?;
+        self.args.get(idx + 1).map(|arg| &arg[..])
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.args.iter().any(|arg| arg == name)
+    }
+
+    // -datadir keeps the stores in DIR/NETWORK, without it they go to the
+    // working directory named after NODE_ID
+    fn data_dir(&self) -> DataDir {
+        match self.option("-datadir") {
+            Some(root) => DataDir::new(
+                root,
+                self.option("-network").unwrap_or(datadir::DEFAULT_NETWORK),
+            ),
+            None => match env::var("NODE_ID") {
+                Ok(node_id) => DataDir::legacy(&node_id),
+                Err(_) => panic!("ERROR: Set -datadir or NODE_ID env. var."),
+            },
+        }
+    }
+
+    fn port(&self) -> String {
+        match self.option("-port") {
+            Some(port) => port.to_string(),
+            None => match env::var("NODE_ID") {
+                Ok(node_id) => node_id,
+                Err(_) => panic!("ERROR: Set -port or NODE_ID env. var."),
+            },
+        }
+    }
+
+    fn coin_selector(&self) -> Box<dyn CoinSelector> {
+        match self.option("-coins") {
+            Some(coins) => Box::new(ManualSelection::new(
+                coins
+                    .split(',')
+                    .map(|outpoint| match outpoint.rfind(':') {
+                        Some(idx) => (
+                            outpoint[..idx].to_string(),
+                            outpoint[idx + 1..]
+                                .parse::<i32>()
+                                .expect("error parsing output index"),
+                        ),
+                        None => panic!("ERROR: Coin {} must be TXID:VOUT", outpoint),
+                    })
+                    .collect(),
+            )),
+            None => coin_selection::selector_by_name(self.option("-strategy").unwrap_or("auto")),
+        }
     }
 
     fn validate_args(&self) {
@@ -39,31 +141,43 @@ impl<'a> CLI<'a> {
         }
     }
 
-    fn create_blockchain(&self, node_id: &str, address: &str) {
+    fn create_blockchain(&self, data_dir: &DataDir, address: &str) {
         if !Wallet::validate_address(address) {
             panic!("ERROR: Address is not valid");
         }
-        let mut bc = Blockchain::create(node_id, address);
-        let mut utxo_set = UTXOSet::new(node_id);
-        utxo_set.reindex(node_id, &mut bc);
+        let mut bc = Blockchain::create(data_dir, address);
+        let mut utxo_set = UTXOSet::new(data_dir);
+        utxo_set.reindex(&mut bc);
 
         println!("Success!");
     }
 
-    fn create_wallet(&self, node_id: &str) {
-        let mut wallets = Wallets::new(node_id);
+    // Opens the chain and the UTXO set, finishing a commit a crash interrupted
+    fn open_chain(data_dir: &DataDir) -> (Blockchain, UTXOSet) {
+        let mut bc = Blockchain::new(data_dir);
+        let mut utxo_set = UTXOSet::new(data_dir);
+
+        if utxo_set.recover(&mut bc) {
+            println!("Recovered the UTXO set up to block {}", bc.tip());
+        }
+
+        (bc, utxo_set)
+    }
+
+    fn create_wallet(&self, data_dir: &DataDir) {
+        let mut wallets = Wallets::new(data_dir);
         let address = wallets.create_wallet();
         println!("Your new address: {}", address);
     }
 
-    fn get_balance(&self, node_id: &str, address: &str) {
+    fn get_balance(&self, data_dir: &DataDir, address: &str) {
         if !Wallet::validate_address(address) {
             panic!("ERROR: Address is not valid");
         }
 
-        let mut utxo_set = UTXOSet::new(node_id);
+        let mut utxo_set = UTXOSet::new(data_dir);
 
-        let mut balance = 0;
+        let mut balance = Amount::ZERO;
         let pub_key_hash = bs58::decode(address)
             .into_vec()
             .expect("error decoding address using base 58");
@@ -71,14 +185,16 @@ impl<'a> CLI<'a> {
         let utxos = utxo_set.find_utxo(&pub_key_hash[..]);
 
         for out in utxos {
-            balance += out.value();
+            balance = balance
+                .checked_add(out.value())
+                .expect("error, balance exceeds the supply limit");
         }
 
         println!("Balance of {}: {}", address, balance);
     }
 
-    fn list_addresses(&self, node_id: &str) {
-        let mut wallets = Wallets::new(node_id);
+    fn list_addresses(&self, data_dir: &DataDir) {
+        let mut wallets = Wallets::new(data_dir);
         let addresses = wallets.get_addresses();
 
         for address in addresses {
@@ -86,32 +202,285 @@ impl<'a> CLI<'a> {
         }
     }
 
-    fn print_chain(&self, node_id: &str) {
-        let mut bc = Blockchain::new(node_id);
+    fn print_chain(&self, data_dir: &DataDir) {
+        let mut bc = Blockchain::new(data_dir);
         for block in bc.iter() {
-            println!("============ Block {} ============", block.hash());
-            println!("Prev. block: {}", block.prev_block_hash());
-            let pow = ProofOfWork::new(&block);
-            println!("PoW: {}\n", pow.validate());
+            CLI::print_block(&block);
+        }
+
+        if let Some(height) = bc.pruned_height() {
+            println!("Blocks up to height {} are pruned", height);
+        }
+    }
+
+    fn get_block(&self, data_dir: &DataDir, height: i32) {
+        match Blockchain::new(data_dir).get_block_by_height(height) {
+            Some(block) => CLI::print_block(&block),
+            None => panic!("ERROR: No block at height {}", height),
+        }
+    }
+
+    fn print_block(block: &Block) {
+        println!("============ Block {} ============", block.hash());
+        println!("Height: {}", block.height());
+        println!("Prev. block: {}", block.prev_block_hash());
+        println!("UTXO commitment: {}", block.utxo_commitment());
+        let pow = ProofOfWork::new(block);
+        println!("PoW: {}\n", pow.validate());
 
-            for tx in block.transactions() {
+        for tx in block.transactions() {
+            println!("{}", tx.to_string());
+        }
+
+        println!();
+    }
+
+    fn reindex_utxo(&self, data_dir: &DataDir, tx_index: bool) {
+        let mut bc = Blockchain::new(data_dir);
+        let mut utxo_set = UTXOSet::new(data_dir);
+        utxo_set.reindex(&mut bc);
+
+        let count = utxo_set.count_outputs();
+        println!("Done! There are {} unspent outputs in the UTXO set.", count);
+
+        if tx_index || bc.has_tx_index() {
+            let count = bc.reindex_transactions();
+            println!("Indexed {} transactions.", count);
+        }
+    }
+
+    fn get_transaction(&self, data_dir: &DataDir, id: &str) {
+        let mut bc = Blockchain::new(data_dir);
+
+        if !bc.has_tx_index() {
+            panic!("ERROR: Transaction index is disabled, enable it with reindexutxo -txindex");
+        }
+
+        match bc.get_transaction(id) {
+            Some((tx, block)) => {
+                println!("Block: {} (height {})", block.hash(), block.height());
                 println!("{}", tx.to_string());
             }
+            None => panic!("ERROR: Transaction {} is not found", id),
+        }
+    }
+
+    fn get_tx_out_set_info(&self, data_dir: &DataDir) {
+        let mut utxo_set = UTXOSet::new(data_dir);
+        let info = utxo_set.info();
+        let commitment = utxo_set.commitment();
 
-            println!();
+        match &info.best_block {
+            Some(block_hash) => {
+                let block = Blockchain::new(data_dir).get_block(block_hash);
+                println!("Best block: {} (height {})", block_hash, block.height());
+                println!(
+                    "Commitment: {} ({})",
+                    commitment,
+                    if block.utxo_commitment() == commitment {
+                        "matches the best block"
+                    } else {
+                        "DOESN'T match the best block"
+                    }
+                );
+            }
+            None => println!("Best block: none"),
         }
+
+        println!("Outputs: {}", info.outputs);
+        println!("Total amount: {}", info.total);
+        println!("Serialized size: {} bytes", info.serialized_size);
+        println!("Hash: {}", info.hash);
     }
 
-    fn reindex_utxo(&self, node_id: &str) {
-        let mut bc = Blockchain::new(node_id);
-        let mut utxo_set = UTXOSet::new(node_id);
-        utxo_set.reindex(node_id, &mut bc);
+    fn verify_utxo(&self, data_dir: &DataDir, repair: bool) {
+        let mut bc = Blockchain::new(data_dir);
+        let mut utxo_set = UTXOSet::new(data_dir);
+        let diff = utxo_set.verify(&mut bc);
+
+        for (key, entry) in &diff.missing {
+            println!("Missing: {} ({})", key, entry.output().value());
+        }
+
+        for (key, entry) in &diff.extra {
+            println!("Extra: {} ({})", key, entry.output().value());
+        }
+
+        for (key, expected, found) in &diff.mismatched {
+            println!("Mismatched: {}", key);
+            println!("    expected: {:?}", expected);
+            println!("    found: {:?}", found);
+        }
 
-        let count = utxo_set.count_transactions();
-        println!("Done! There are {} transactions in the UTXO set.", count);
+        if diff.is_empty() {
+            println!("UTXO set is consistent with the chain");
+            return;
+        }
+
+        println!(
+            "{} missing, {} extra, {} mismatched",
+            diff.missing.len(),
+            diff.extra.len(),
+            diff.mismatched.len()
+        );
+
+        if repair {
+            utxo_set.repair(&diff, &bc);
+            println!("Repaired!");
+        } else {
+            process::exit(1);
+        }
+    }
+
+    fn dump_utxo(&self, data_dir: &DataDir, path: &str) {
+        let mut bc = Blockchain::new(data_dir);
+        let snapshot = UTXOSet::new(data_dir).dump(&mut bc);
+
+        match fs::write(path, snapshot.serialize()) {
+            Ok(_) => (),
+            Err(err) => panic!("error writing file {}: {}", path, err),
+        };
+
+        println!(
+            "Dumped {} outputs at block {} (height {}), content hash {}",
+            snapshot.len(),
+            snapshot.block_hash(),
+            snapshot.height(),
+            snapshot.content_hash()
+        );
     }
 
-    fn send(&self, node_id: &str, from: &str, to: &str, amount: i32, mine_now: bool) {
+    fn load_utxo(&self, data_dir: &DataDir, path: &str) {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) => panic!("error reading file {}: {}", path, err),
+        };
+        let snapshot = UTXOSnapshot::deserialize(bytes);
+        let mut bc = Blockchain::new(data_dir);
+
+        println!(
+            "Loading {} outputs at block {} (height {}), content hash {}",
+            snapshot.len(),
+            snapshot.block_hash(),
+            snapshot.height(),
+            snapshot.content_hash()
+        );
+        UTXOSet::new(data_dir).load(snapshot, &mut bc);
+        println!("Done! The snapshot will be validated in the background by startnode.");
+    }
+
+    fn export_chain(&self, data_dir: &DataDir, path: &str) {
+        let mut bc = Blockchain::new(data_dir);
+
+        if let Some(height) = bc.pruned_height() {
+            panic!(
+                "ERROR: Blocks up to height {} are pruned, the chain can't be exported",
+                height
+            );
+        }
+
+        let block_hashes = bc.get_block_hashes();
+        let mut writer = BootstrapWriter::create(path);
+
+        for block_hash in block_hashes.iter().rev() {
+            writer.write_block(&bc.get_block(block_hash));
+        }
+
+        writer.finish();
+        println!(
+            "Exported {} blocks up to {} to {}",
+            block_hashes.len(),
+            bc.tip(),
+            path
+        );
+    }
+
+    // Blocks of the file go through the same checks as blocks from peers
+    fn import_chain(&self, data_dir: &DataDir, path: &str) {
+        let mut blocks = BootstrapReader::open(path);
+        let genesis = match blocks.next() {
+            Some(genesis) => genesis,
+            None => panic!("ERROR: {} holds no blocks", path),
+        };
+
+        let mut imported = 0;
+        let mut known = 0;
+
+        if Blockchain::exists_in(data_dir) {
+            known += 1;
+        } else {
+            let mut bc = Blockchain::create_from_genesis(data_dir, &genesis);
+            UTXOSet::new(data_dir).reindex(&mut bc);
+            imported += 1;
+        }
+
+        let (mut bc, mut utxo_set) = CLI::open_chain(data_dir);
+
+        if bc.get_block_hash(0).as_deref() != Some(genesis.hash()) {
+            panic!("ERROR: {} starts from a different genesis block", path);
+        }
+
+        for block in blocks {
+            if bc.is_on_active_chain(block.hash()) {
+                known += 1;
+                continue;
+            }
+
+            if let Err(err) = bc.validate_block(&block, &mut utxo_set) {
+                panic!("ERROR: Invalid block at height {}: {}", block.height(), err);
+            }
+
+            bc.add_block(&block);
+            utxo_set.update(&block);
+            bc.prune(&mut utxo_set);
+            imported += 1;
+        }
+
+        println!(
+            "Imported {} blocks, {} were already known. New tip: {}",
+            imported,
+            known,
+            bc.tip()
+        );
+    }
+
+    fn rollback(&self, data_dir: &DataDir, blocks: usize) {
+        let (mut bc, mut utxo_set) = CLI::open_chain(data_dir);
+
+        if let Some(height) = bc.pruned_height() {
+            if bc.get_best_height() - blocks as i32 <= height {
+                panic!(
+                    "ERROR: Can't roll back to height {} or below, older blocks are pruned",
+                    height
+                );
+            }
+        }
+
+        for _ in 0..blocks {
+            let tip = bc.tip().to_string();
+            let block = bc.get_block(&tip);
+
+            if block.prev_block_hash().is_empty() {
+                panic!("ERROR: Genesis block can't be disconnected");
+            }
+
+            utxo_set.disconnect_block(&block);
+            bc.set_tip(block.prev_block_hash());
+            println!("Disconnected block {} at height {}", block.hash(), block.height());
+        }
+
+        println!("New tip: {}", bc.tip());
+    }
+
+    fn send(
+        &self,
+        data_dir: &DataDir,
+        from: &str,
+        to: &str,
+        amount: Amount,
+        mine_now: bool,
+        selector: &dyn CoinSelector,
+    ) {
         if !Wallet::validate_address(from) {
             panic!("ERROR: Sender address is not valid");
         }
@@ -120,39 +489,324 @@ impl<'a> CLI<'a> {
             panic!("ERROR: Recipient address is not valid");
         }
 
-        let mut bc = Blockchain::new(node_id);
-        let mut utxo_set = UTXOSet::new(node_id);
-        let wallet = Wallets::new(node_id).get_wallet(from);
-        let tx = Transaction::new_utxo_tx(&wallet, to, amount, &mut bc, &mut utxo_set);
+        let (mut bc, mut utxo_set) = CLI::open_chain(data_dir);
+        let wallet = Wallets::new(data_dir).get_wallet(from);
+        let tx = Transaction::new_utxo_tx(&wallet, to, amount, &mut bc, &mut utxo_set, selector);
+
+        if mine_now {
+            let cbtx = Transaction::new_coin_base_tx(from, "");
+            let block = bc.mine_block(vec![cbtx, tx], &mut utxo_set);
+            utxo_set.update(&block);
+        } else {
+            self.submit_tx(&tx);
+        }
+
+        println!("Success!");
+    }
+
+    fn parse_amount(amount: &str) -> Amount {
+        match amount.parse::<Amount>() {
+            Ok(amount) => amount,
+            Err(err) => panic!("ERROR: {}", err),
+        }
+    }
+
+    fn parse_recipients(list: &str) -> Vec<(String, Amount)> {
+        list.split(',')
+            .map(|pair| {
+                let mut parts = pair.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(address), Some(amount)) => (
+                        address.trim().to_string(),
+                        CLI::parse_amount(amount),
+                    ),
+                    _ => panic!("ERROR: Recipient {} must be ADDRESS:AMOUNT", pair),
+                }
+            })
+            .collect()
+    }
+
+    fn read_recipients(path: &str) -> Vec<(String, Amount)> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => panic!("error reading file {}: {}", path, err),
+        };
+        let mut recipients = Vec::new();
+
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ',');
+            match (parts.next(), parts.next()) {
+                (Some(address), Some(amount)) => recipients.push((
+                    address.trim().to_string(),
+                    match amount.parse::<Amount>() {
+                        Ok(amount) => amount,
+                        Err(err) => panic!("ERROR: Line {}: {}", n + 1, err),
+                    },
+                )),
+                _ => panic!("ERROR: Line {} must be ADDRESS,AMOUNT", n + 1),
+            }
+        }
+
+        recipients
+    }
+
+    fn send_many(
+        &self,
+        data_dir: &DataDir,
+        from: &str,
+        recipients: &[(String, Amount)],
+        mine_now: bool,
+        selector: &dyn CoinSelector,
+    ) {
+        if !Wallet::validate_address(from) {
+            panic!("ERROR: Sender address is not valid");
+        }
+
+        let invalid: Vec<&str> = recipients
+            .iter()
+            .filter(|(to, _)| !Wallet::validate_address(to))
+            .map(|(to, _)| &to[..])
+            .collect();
+
+        if !invalid.is_empty() {
+            panic!("ERROR: Recipient addresses are not valid: {}", invalid.join(", "));
+        }
+
+        let (mut bc, mut utxo_set) = CLI::open_chain(data_dir);
+        let wallet = Wallets::new(data_dir).get_wallet(from);
+        let tx = Transaction::new_batch_utxo_tx(
+            &wallet,
+            recipients,
+            &mut bc,
+            &mut utxo_set,
+            selector,
+        );
+        let saved = tx.batch_space_saved(recipients.len());
 
         if mine_now {
             let cbtx = Transaction::new_coin_base_tx(from, "");
-            let block = bc.mine_block(vec![cbtx, tx]);
+            let block = bc.mine_block(vec![cbtx, tx], &mut utxo_set);
             utxo_set.update(&block);
         } else {
-            let cmd = b"tx\n";
-            let data = TxWrapper{
-                addr_from: format!("127.0.0.1:{}", node_id),
-                tx: tx.serialize(),
-            };
-            let payload = bincode::serialize(&data).unwrap();
-            let mut request = Vec::new();
-            request.extend(cmd);
-            request.extend(payload);
-            match TcpStream::connect("127.0.0.1:3000") {
-                Ok(mut stream) => {
-                    stream.write(&request).unwrap();
-                    stream.flush().unwrap();
-                },
-                Err(e) => panic!("{}", e),
-            };
+            self.submit_tx(&tx);
+        }
+
+        println!(
+            "Success! Paid {} recipients in one transaction, bytes saved: at least {}",
+            recipients.len(),
+            saved
+        );
+    }
+
+    fn preview_send(&self, data_dir: &DataDir, from: &str, amount: Amount, selector: &dyn CoinSelector) {
+        if !Wallet::validate_address(from) {
+            panic!("ERROR: Sender address is not valid");
+        }
+
+        let mut utxo_set = UTXOSet::new(data_dir);
+        let wallet = Wallets::new(data_dir).get_wallet(from);
+        let pub_key_hash = Wallet::hash_pub_key(wallet.public_key());
+
+        match utxo_set.select_coins(&pub_key_hash, amount, selector) {
+            Some(selection) => {
+                for coin in selection.coins() {
+                    println!("Input {}:{} - {}", coin.tx_id(), coin.v_out(), coin.value());
+                }
+
+                println!("Total: {}", selection.total());
+                println!("Change: {}", selection.change());
+            }
+            None => println!("No inputs cover {} with the chosen strategy", amount),
+        }
+    }
+
+    fn list_unspent(&self, data_dir: &DataDir, address: &str) {
+        if !Wallet::validate_address(address) {
+            panic!("ERROR: Address is not valid");
+        }
+
+        let mut utxo_set = UTXOSet::new(data_dir);
+        let pub_key_hash = bs58::decode(address)
+            .into_vec()
+            .expect("error decoding address using base 58");
+        let pub_key_hash = pub_key_hash[1..pub_key_hash.len() - 4].to_vec();
+
+        for coin in utxo_set.find_spendable_coins(&pub_key_hash) {
+            let entry = utxo_set
+                .get(coin.tx_id(), coin.v_out())
+                .expect("error, spendable coin is missing from the UTXO set");
+            println!(
+                "{}:{} - {} (height {}{})",
+                coin.tx_id(),
+                coin.v_out(),
+                coin.value(),
+                entry.height(),
+                if entry.is_coinbase() { ", coinbase" } else { "" }
+            );
+        }
+    }
+
+    fn parse_policy(policy: &str) -> Policy {
+        match Policy::parse(policy) {
+            Ok(policy) => policy,
+            Err(err) => panic!("ERROR: Invalid policy: {}", err),
+        }
+    }
+
+    fn get_pub_key(&self, data_dir: &DataDir, address: &str) {
+        let wallet = Wallets::new(data_dir).get_wallet(address);
+        println!("{}", CLI::to_hex(wallet.public_key()));
+    }
+
+    fn compile_policy(&self, policy: &Policy) {
+        let analysis = policy.analyze();
+
+        println!("Policy: {}", policy);
+        println!("Address: {}", policy.address());
+        println!("Keys: {}", analysis.keys);
+        println!("Signatures needed: {}", analysis.min_signatures);
+        println!("Witness size: {} bytes", analysis.min_witness_size);
+
+        if analysis.timelock > 0 {
+            println!("Spendable from height: {}", analysis.timelock);
+        }
+    }
+
+    fn spend_policy(
+        &self,
+        data_dir: &DataDir,
+        policy: Policy,
+        to: &str,
+        amount: Amount,
+        mine_now: bool,
+        selector: &dyn CoinSelector,
+    ) {
+        if !Wallet::validate_address(to) {
+            panic!("ERROR: Recipient address is not valid");
+        }
+
+        let from = policy.address();
+        let mut wallets = Wallets::new(data_dir);
+        let mut satisfier = Satisfier::new(policy);
+
+        for address in wallets.get_addresses() {
+            let wallet = wallets.get_wallet(&address);
+
+            if satisfier.policy().keys().contains(&wallet.public_key()) {
+                satisfier.add_key(wallet.public_key(), wallet.pkcs8_bytes());
+            }
+        }
+
+        let (mut bc, mut utxo_set) = CLI::open_chain(data_dir);
+        let tx = Transaction::new_policy_tx(
+            &satisfier,
+            &[(to.to_string(), amount)],
+            &mut bc,
+            &mut utxo_set,
+            selector,
+        );
+
+        if mine_now {
+            let cbtx = Transaction::new_coin_base_tx(&from, "");
+            let block = bc.mine_block(vec![cbtx, tx], &mut utxo_set);
+            utxo_set.update(&block);
+        } else {
+            self.submit_tx(&tx);
         }
 
         println!("Success!");
     }
 
-    fn start_node(&self, node_id: &str, miner_address: &str) {
-        println!("Starting node {}", node_id);
+    fn submit_tx(&self, tx: &Transaction) {
+        let cmd = b"tx\n";
+        let data = TxWrapper{
+            addr_from: format!("127.0.0.1:{}", self.port()),
+            tx: tx.serialize(),
+        };
+        let payload = bincode::serialize(&data).unwrap();
+        let mut request = Vec::new();
+        request.extend(cmd);
+        request.extend(payload);
+        match TcpStream::connect("127.0.0.1:3000") {
+            Ok(mut stream) => {
+                stream.write(&request).unwrap();
+                stream.flush().unwrap();
+            },
+            Err(e) => panic!("{}", e),
+        };
+    }
+
+    fn hash_file(path: &str) -> Vec<u8> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(err) => panic!("error reading file {}: {}", path, err),
+        };
+        let mut hasher = Sha256::new();
+        let mut digest: [u8; 32] = [0; 32];
+        hasher.input(&content);
+        hasher.result(&mut digest);
+        digest.to_vec()
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn notarize(&self, data_dir: &DataDir, path: &str, address: &str, mine_now: bool) {
+        if !Wallet::validate_address(address) {
+            panic!("ERROR: Address is not valid");
+        }
+
+        let digest = CLI::hash_file(path);
+        let (mut bc, mut utxo_set) = CLI::open_chain(data_dir);
+        let wallet = Wallets::new(data_dir).get_wallet(address);
+        let tx = Transaction::new_data_carrier_tx(&wallet, &digest, &mut bc, &mut utxo_set);
+
+        if mine_now {
+            let cbtx = Transaction::new_coin_base_tx(address, "");
+            let block = bc.mine_block(vec![cbtx, tx], &mut utxo_set);
+            utxo_set.update(&block);
+            println!("Notarized {} in block {}", CLI::to_hex(&digest), block.hash());
+        } else {
+            self.submit_tx(&tx);
+            println!("Notarized {} in transaction {}", CLI::to_hex(&digest), tx.id());
+        }
+    }
+
+    fn verify_notary(&self, data_dir: &DataDir, path: &str) {
+        let digest = CLI::hash_file(path);
+        let mut bc = Blockchain::new(data_dir);
+
+        match bc.find_data_carrier(&digest) {
+            DataCarrierLookup::Found(block) => println!(
+                "Document {} was notarized in block {} (height {}) at {}",
+                CLI::to_hex(&digest),
+                block.hash(),
+                block.height(),
+                block.timestamp()
+            ),
+            DataCarrierLookup::NotFound => println!(
+                "Document {} was not found on chain",
+                CLI::to_hex(&digest)
+            ),
+            DataCarrierLookup::Pruned(height) => println!(
+                "Document {} was not found above height {}, the history below was pruned",
+                CLI::to_hex(&digest),
+                height
+            ),
+        }
+    }
+
+    fn start_node(&self, data_dir: &DataDir, miner_address: &str) {
+        let node_id = data_dir.node_id();
+        let port = self.port();
+        println!("Starting node {} on port {}", node_id, port);
         if !miner_address.is_empty() {
             if Wallet::validate_address(miner_address) {
                 println!(
@@ -164,53 +818,148 @@ impl<'a> CLI<'a> {
             }
         }
 
-        Server::start(node_id, miner_address);
+        let target = match (self.option("-prune"), self.option("-prunesize")) {
+            (Some(depth), None) => Some(PruneTarget::Depth(
+                depth.parse().expect("ERROR: -prune expects a number of blocks"),
+            )),
+            (None, Some(size)) => Some(PruneTarget::Size(
+                size.parse::<u64>().expect("ERROR: -prunesize expects a size in MB") * 1024 * 1024,
+            )),
+            (None, None) => None,
+            _ => panic!("ERROR: Use either -prune or -prunesize"),
+        };
+
+        if let Some(target) = target {
+            Blockchain::new(data_dir).set_prune_target(target);
+        }
+
+        Server::start(data_dir, &node_id, &port, miner_address);
     }
 
     pub fn run(&self) {
         self.validate_args();
-        let node_id = std::env::var("NODE_ID").expect("error reading NODE_ID from env");
+        let data_dir = self.data_dir();
+        let _lock = data_dir.lock();
 
         match self.args[1].as_ref() {
             "createblockchain" => match self.args[2].as_ref() {
-                "-address" => self.create_blockchain(&node_id, &self.args[3]),
+                "-address" => self.create_blockchain(&data_dir, &self.args[3]),
                 _ => self.print_usage(),
             },
-            "createwallet" => self.create_wallet(&node_id),
+            "createwallet" => self.create_wallet(&data_dir),
+            "dumputxo" => match self.option("-file") {
+                Some(path) => self.dump_utxo(&data_dir, path),
+                None => self.print_usage(),
+            },
+            "exportchain" => match self.option("-file") {
+                Some(path) => self.export_chain(&data_dir, path),
+                None => self.print_usage(),
+            },
+            "importchain" => match self.option("-file") {
+                Some(path) => self.import_chain(&data_dir, path),
+                None => self.print_usage(),
+            },
+            "loadutxo" => match self.option("-file") {
+                Some(path) => self.load_utxo(&data_dir, path),
+                None => self.print_usage(),
+            },
             "getbalance" => match self.args[2].as_ref() {
-                "-address" => self.get_balance(&node_id, &self.args[3]),
+                "-address" => self.get_balance(&data_dir, &self.args[3]),
                 _ => panic!("invalid argument to command"),
             },
-            "listaddresses" => self.list_addresses(&node_id),
-            "printchain" => self.print_chain(&node_id),
-            "reindexutxo" => self.reindex_utxo(&node_id),
-            "send" => match self.args[2].as_ref() {
-                "-from" => match self.args[4].as_ref() {
-                    "-to" => match self.args[6].as_ref() {
-                        "-amount" => match self.args[8].as_ref() {
-                            "-mine" => self.send(
-                                &node_id,
-                                &self.args[3],
-                                &self.args[5],
-                                self.args[7].parse::<i32>().unwrap(),
-                                true,
-                            ),
-                            _ => self.send(
-                                &node_id,
-                                &self.args[3],
-                                &self.args[5],
-                                self.args[7].parse::<i32>().unwrap(),
-                                false,
-                            ),
-                        },
-                        _ => self.print_usage(),
-                    },
-                    _ => self.print_usage(),
-                },
+            "getblock" => match self.option("-height") {
+                Some(height) => self.get_block(
+                    &data_dir,
+                    height.parse::<i32>().expect("error parsing block height"),
+                ),
+                None => self.print_usage(),
+            },
+            "gettransaction" => match self.option("-id") {
+                Some(id) => self.get_transaction(&data_dir, id),
+                None => self.print_usage(),
+            },
+            "gettxoutsetinfo" => self.get_tx_out_set_info(&data_dir),
+            "getpubkey" => match self.option("-address") {
+                Some(address) => self.get_pub_key(&data_dir, address),
+                None => self.print_usage(),
+            },
+            "compilepolicy" => match self.option("-policy") {
+                Some(policy) => self.compile_policy(&CLI::parse_policy(policy)),
+                None => self.print_usage(),
+            },
+            "spendpolicy" => match (self.option("-policy"), self.option("-to"), self.option("-amount")) {
+                (Some(policy), Some(to), Some(amount)) => self.spend_policy(
+                    &data_dir,
+                    CLI::parse_policy(policy),
+                    to,
+                    CLI::parse_amount(amount),
+                    self.flag("-mine"),
+                    &*self.coin_selector(),
+                ),
+                _ => self.print_usage(),
+            },
+            "listaddresses" => self.list_addresses(&data_dir),
+            "notarize" => match (self.option("-file"), self.option("-address")) {
+                (Some(path), Some(address)) => self.notarize(&data_dir, path, address, self.flag("-mine")),
+                _ => self.print_usage(),
+            },
+            "printchain" => self.print_chain(&data_dir),
+            "reindexutxo" => self.reindex_utxo(&data_dir, self.flag("-txindex")),
+            "rollback" => match self.option("-blocks") {
+                Some(blocks) => self.rollback(
+                    &data_dir,
+                    blocks.parse::<usize>().expect("error parsing number of blocks"),
+                ),
+                None => self.print_usage(),
+            },
+            "send" => match (self.option("-from"), self.option("-to"), self.option("-amount")) {
+                (Some(from), Some(to), Some(amount)) => self.send(
+                    &data_dir,
+                    from,
+                    to,
+                    CLI::parse_amount(amount),
+                    self.flag("-mine"),
+                    &*self.coin_selector(),
+                ),
+                _ => self.print_usage(),
+            },
+            "sendmany" => match (self.option("-from"), self.option("-to"), self.option("-file")) {
+                (Some(from), Some(list), None) => self.send_many(
+                    &data_dir,
+                    from,
+                    &CLI::parse_recipients(list),
+                    self.flag("-mine"),
+                    &*self.coin_selector(),
+                ),
+                (Some(from), None, Some(path)) => self.send_many(
+                    &data_dir,
+                    from,
+                    &CLI::read_recipients(path),
+                    self.flag("-mine"),
+                    &*self.coin_selector(),
+                ),
                 _ => self.print_usage(),
             },
+            "previewsend" => match (self.option("-from"), self.option("-amount")) {
+                (Some(from), Some(amount)) => self.preview_send(
+                    &data_dir,
+                    from,
+                    CLI::parse_amount(amount),
+                    &*self.coin_selector(),
+                ),
+                _ => self.print_usage(),
+            },
+            "listunspent" => match self.option("-address") {
+                Some(address) => self.list_unspent(&data_dir, address),
+                None => self.print_usage(),
+            },
+            "verifyutxo" => self.verify_utxo(&data_dir, self.flag("-repair")),
+            "verifynotary" => match self.option("-file") {
+                Some(path) => self.verify_notary(&data_dir, path),
+                None => self.print_usage(),
+            },
             "startnode" => match self.args[2].as_ref() {
-                "-miner" => self.start_node(&node_id, &self.args[3]),
+                "-miner" => self.start_node(&data_dir, &self.args[3]),
                 _ => self.print_usage(),
             },
             _ => self.print_usage(),
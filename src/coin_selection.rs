@@ -0,0 +1,268 @@
+use crate::amount::Amount;
+
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct Coin {
+    tx_id: String,
+    v_out: i32,
+    value: Amount,
+}
+
+impl Coin {
+    pub fn new(tx_id: &str, v_out: i32, value: Amount) -> Coin {
+        Coin {
+            tx_id: tx_id.to_string(),
+            v_out,
+            value,
+        }
+    }
+
+    pub fn tx_id(&self) -> &str {
+        &self.tx_id[..]
+    }
+
+    pub fn v_out(&self) -> i32 {
+        self.v_out
+    }
+
+    pub fn value(&self) -> Amount {
+        self.value
+    }
+}
+
+#[derive(Debug)]
+pub struct Selection {
+    coins: Vec<Coin>,
+    target: Amount,
+}
+
+impl Selection {
+    pub fn coins(&self) -> &[Coin] {
+        &self.coins
+    }
+
+    pub fn total(&self) -> Amount {
+        Amount::checked_sum(self.coins.iter().map(|coin| coin.value()))
+            .expect("error, selected coins exceed the supply limit")
+    }
+
+    pub fn change(&self) -> Amount {
+        self.total()
+            .checked_sub(self.target)
+            .expect("error, selected coins don't cover the target")
+    }
+}
+
+pub trait CoinSelector {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Selection>;
+}
+
+fn accumulate(coins: Vec<Coin>, target: Amount) -> Option<Selection> {
+    let mut selected = Vec::new();
+    let mut total = Amount::ZERO;
+
+    for coin in coins {
+        if total >= target {
+            break;
+        }
+
+        total = total.checked_add(coin.value())?;
+        selected.push(coin);
+    }
+
+    if total < target {
+        return None;
+    }
+
+    Some(Selection {
+        coins: selected,
+        target,
+    })
+}
+
+// Depth-first search over include/exclude decisions for a set of coins that
+// adds up to exactly the target, so the transaction needs no change output.
+pub struct BranchAndBound;
+
+impl BranchAndBound {
+    fn search(
+        coins: &[Coin],
+        remaining: &[u64],
+        idx: usize,
+        total: u64,
+        target: u64,
+        selected: &mut Vec<usize>,
+        tries: &mut usize,
+    ) -> bool {
+        if total == target {
+            return true;
+        }
+
+        *tries += 1;
+
+        if idx == coins.len() || *tries > BNB_MAX_TRIES || total + remaining[idx] < target {
+            return false;
+        }
+
+        if total + coins[idx].value().base_units() <= target {
+            selected.push(idx);
+
+            if BranchAndBound::search(
+                coins,
+                remaining,
+                idx + 1,
+                total + coins[idx].value().base_units(),
+                target,
+                selected,
+                tries,
+            ) {
+                return true;
+            }
+
+            selected.pop();
+        }
+
+        BranchAndBound::search(coins, remaining, idx + 1, total, target, selected, tries)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Selection> {
+        let mut coins = coins.to_vec();
+        coins.sort_by_key(|coin| Reverse(coin.value()));
+
+        let mut remaining = vec![0u64; coins.len() + 1];
+
+        for idx in (0..coins.len()).rev() {
+            remaining[idx] = remaining[idx + 1] + coins[idx].value().base_units();
+        }
+
+        let mut selected = Vec::new();
+        let mut tries = 0;
+
+        if !BranchAndBound::search(
+            &coins,
+            &remaining,
+            0,
+            0,
+            target.base_units(),
+            &mut selected,
+            &mut tries,
+        ) {
+            return None;
+        }
+
+        Some(Selection {
+            coins: selected.into_iter().map(|idx| coins[idx].clone()).collect(),
+            target,
+        })
+    }
+}
+
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Selection> {
+        let mut coins = coins.to_vec();
+        coins.sort_by_key(|coin| Reverse(coin.value()));
+        accumulate(coins, target)
+    }
+}
+
+pub struct SmallestFirst;
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Selection> {
+        let mut coins = coins.to_vec();
+        coins.sort_by_key(|coin| coin.value());
+        accumulate(coins, target)
+    }
+}
+
+pub struct RandomSelection;
+
+impl CoinSelector for RandomSelection {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Selection> {
+        let mut coins = coins.to_vec();
+        coins.shuffle(&mut thread_rng());
+        accumulate(coins, target)
+    }
+}
+
+pub struct WithFallback {
+    primary: Box<dyn CoinSelector>,
+    fallback: Box<dyn CoinSelector>,
+}
+
+impl WithFallback {
+    pub fn new(primary: Box<dyn CoinSelector>, fallback: Box<dyn CoinSelector>) -> WithFallback {
+        WithFallback { primary, fallback }
+    }
+}
+
+impl CoinSelector for WithFallback {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Selection> {
+        match self.primary.select(coins, target) {
+            Some(selection) => Some(selection),
+            None => self.fallback.select(coins, target),
+        }
+    }
+}
+
+pub struct ManualSelection {
+    outpoints: Vec<(String, i32)>,
+}
+
+impl ManualSelection {
+    // An outpoint given more than once is spent, and counted, once
+    pub fn new(mut outpoints: Vec<(String, i32)>) -> ManualSelection {
+        let mut seen = HashSet::new();
+        outpoints.retain(|outpoint| seen.insert(outpoint.clone()));
+        ManualSelection { outpoints }
+    }
+}
+
+impl CoinSelector for ManualSelection {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Selection> {
+        let mut selected = Vec::new();
+
+        // An outpoint that isn't spendable fails the selection like a short total
+        for (tx_id, v_out) in &self.outpoints {
+            let coin = coins
+                .iter()
+                .find(|coin| coin.tx_id() == tx_id && coin.v_out() == *v_out)?;
+            selected.push(coin.clone());
+        }
+
+        let selection = Selection {
+            coins: selected,
+            target,
+        };
+
+        if selection.total() < target {
+            return None;
+        }
+
+        Some(selection)
+    }
+}
+
+pub fn selector_by_name(name: &str) -> Box<dyn CoinSelector> {
+    match name {
+        "auto" => Box::new(WithFallback::new(
+            Box::new(BranchAndBound),
+            Box::new(LargestFirst),
+        )),
+        "bnb" => Box::new(BranchAndBound),
+        "largest" => Box::new(LargestFirst),
+        "smallest" => Box::new(SmallestFirst),
+        "random" => Box::new(RandomSelection),
+        name => panic!("Unknown coin selection strategy: {}", name),
+    }
+}
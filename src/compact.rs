@@ -0,0 +1,175 @@
+use crate::amount::Amount;
+use crate::transaction::{Lock, TXOutput};
+
+// Leading byte of every compact record, bumped when the layout changes
+pub const VERSION: u8 = 1;
+
+const HASH_SIZE: usize = 20;
+const TX_ID_SIZE: usize = 32;
+
+// Locks of the usual size are stored as a template byte and the bare hash,
+// anything else falls back to the raw form with a length prefix
+const TEMPLATE_PUB_KEY_HASH: u8 = 0;
+const TEMPLATE_POLICY_HASH: u8 = 1;
+const RAW_PUB_KEY_HASH: u8 = 2;
+const RAW_POLICY_HASH: u8 = 3;
+const RAW_DATA: u8 = 4;
+
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+
+    buf.push(value as u8);
+}
+
+pub fn read_varint(data: &mut &[u8]) -> u64 {
+    let mut value = 0u64;
+    let mut shift = 0;
+
+    loop {
+        let byte = read_byte(data);
+
+        if shift > 63 || (shift == 63 && byte > 1) {
+            panic!("error decoding varint, value overflows u64");
+        }
+
+        value |= u64::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+
+        shift += 7;
+    }
+}
+
+fn read_byte(data: &mut &[u8]) -> u8 {
+    read_bytes(data, 1)[0]
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8], len: usize) -> &'a [u8] {
+    if data.len() < len {
+        panic!("error decoding compact record, unexpected end of data");
+    }
+
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    bytes
+}
+
+pub fn write_output(buf: &mut Vec<u8>, output: &TXOutput) {
+    write_varint(buf, output.value().base_units());
+
+    let (template, bytes) = match output.lock() {
+        Lock::PubKeyHash(hash) if hash.len() == HASH_SIZE => (TEMPLATE_PUB_KEY_HASH, hash),
+        Lock::PolicyHash(hash) if hash.len() == HASH_SIZE => (TEMPLATE_POLICY_HASH, hash),
+        Lock::PubKeyHash(hash) => (RAW_PUB_KEY_HASH, hash),
+        Lock::PolicyHash(hash) => (RAW_POLICY_HASH, hash),
+        Lock::Data(data) => (RAW_DATA, data),
+    };
+
+    buf.push(template);
+
+    if template != TEMPLATE_PUB_KEY_HASH && template != TEMPLATE_POLICY_HASH {
+        write_varint(buf, bytes.len() as u64);
+    }
+
+    buf.extend_from_slice(bytes);
+}
+
+pub fn read_output(data: &mut &[u8]) -> TXOutput {
+    let value = match Amount::from_base_units(read_varint(data)) {
+        Some(value) => value,
+        None => panic!("error decoding output, amount exceeds the supply limit"),
+    };
+
+    let template = read_byte(data);
+    let len = match template {
+        TEMPLATE_PUB_KEY_HASH | TEMPLATE_POLICY_HASH => HASH_SIZE,
+        RAW_PUB_KEY_HASH | RAW_POLICY_HASH | RAW_DATA => read_varint(data) as usize,
+        _ => panic!("error decoding output, unknown lock template {}", template),
+    };
+    let bytes = read_bytes(data, len).to_vec();
+
+    let lock = match template {
+        TEMPLATE_PUB_KEY_HASH | RAW_PUB_KEY_HASH => Lock::PubKeyHash(bytes),
+        TEMPLATE_POLICY_HASH | RAW_POLICY_HASH => Lock::PolicyHash(bytes),
+        _ => Lock::Data(bytes),
+    };
+
+    TXOutput::from_lock(value, lock)
+}
+
+fn split_outpoint(key: &str) -> (&str, u64) {
+    let mut parts = key.splitn(2, ':');
+
+    match (parts.next(), parts.next().map(|v_out| v_out.parse::<u64>())) {
+        (Some(tx_id), Some(Ok(v_out))) if tx_id.len() == TX_ID_SIZE * 2 => (tx_id, v_out),
+        _ => panic!("error encoding outpoint {}, TXID:VOUT expected", key),
+    }
+}
+
+fn write_tx_id(buf: &mut Vec<u8>, tx_id: &str) {
+    for i in (0..tx_id.len()).step_by(2) {
+        match u8::from_str_radix(&tx_id[i..i + 2], 16) {
+            Ok(byte) => buf.push(byte),
+            Err(_) => panic!("error encoding outpoint, {} is not a hex TXID", tx_id),
+        }
+    }
+}
+
+// Consecutive outpoints of the same transaction share a single copy of the
+// TXID: a run is stored as the binary TXID, the number of outputs and each VOUT
+pub fn write_outpoints(buf: &mut Vec<u8>, outpoints: &[String]) {
+    let mut runs: Vec<(&str, Vec<u64>)> = Vec::new();
+
+    for key in outpoints {
+        let (tx_id, v_out) = split_outpoint(key);
+
+        match runs.last_mut() {
+            Some((last, v_outs)) if *last == tx_id => v_outs.push(v_out),
+            _ => runs.push((tx_id, vec![v_out])),
+        }
+    }
+
+    write_varint(buf, runs.len() as u64);
+
+    for (tx_id, v_outs) in runs {
+        write_tx_id(buf, tx_id);
+        write_varint(buf, v_outs.len() as u64);
+
+        for v_out in v_outs {
+            write_varint(buf, v_out);
+        }
+    }
+}
+
+pub fn read_outpoints(data: &mut &[u8]) -> Vec<String> {
+    let mut outpoints = Vec::new();
+
+    for _ in 0..read_varint(data) {
+        let tx_id: String = read_bytes(data, TX_ID_SIZE)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        for _ in 0..read_varint(data) {
+            outpoints.push(format!("{}:{}", tx_id, read_varint(data)));
+        }
+    }
+
+    outpoints
+}
+
+pub fn check_version(data: &mut &[u8], what: &str) {
+    let version = read_byte(data);
+
+    if version != VERSION {
+        panic!(
+            "error decoding {}, unknown record version {}",
+            what, version
+        );
+    }
+}
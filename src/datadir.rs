@@ -0,0 +1,93 @@
+use fs2::FileExt;
+use rand::Rng;
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+
+pub const DEFAULT_NETWORK: &str = "main";
+const LOCK_FILE: &str = "LOCK";
+const NODE_ID_FILE: &str = "node_id";
+
+// Where a node keeps its stores. With a root given they live in ROOT/NETWORK,
+// otherwise in the working directory with NODE_ID in their names.
+#[derive(Clone)]
+pub struct DataDir {
+    dir: PathBuf,
+    suffix: Option<String>,
+}
+
+impl DataDir {
+    pub fn new(root: &str, network: &str) -> DataDir {
+        let dir = PathBuf::from(root).join(network);
+
+        match fs::create_dir_all(&dir) {
+            Ok(_) => (),
+            Err(err) => panic!("error creating data directory {}: {}", dir.display(), err),
+        };
+
+        DataDir { dir, suffix: None }
+    }
+
+    pub fn legacy(node_id: &str) -> DataDir {
+        DataDir {
+            dir: PathBuf::from("."),
+            suffix: Some(node_id.to_string()),
+        }
+    }
+
+    // Path of the file or store NAME, e.g. path("blockchain", "sled")
+    pub fn path(&self, name: &str, extension: &str) -> String {
+        let mut file = match &self.suffix {
+            Some(suffix) => format!("{}_{}", name, suffix),
+            None => name.to_string(),
+        };
+
+        if !extension.is_empty() {
+            file = format!("{}.{}", file, extension);
+        }
+
+        self.dir.join(file).to_string_lossy().to_string()
+    }
+
+    // Held until the returned file is dropped, a second process gets an error
+    pub fn lock(&self) -> File {
+        let path = self.path(LOCK_FILE, "");
+        let file = match OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(err) => panic!("error opening lock file {}: {}", path, err),
+        };
+
+        if file.try_lock_exclusive().is_err() {
+            panic!(
+                "ERROR: Data in {} is used by another process",
+                self.dir.display()
+            );
+        }
+
+        file
+    }
+
+    // Random identity generated on first use and kept with the data, so it
+    // stays the same whatever port the node listens on
+    pub fn node_id(&self) -> String {
+        let path = self.path(NODE_ID_FILE, "");
+
+        if let Ok(node_id) = fs::read_to_string(&path) {
+            return node_id.trim().to_string();
+        }
+
+        let bytes: [u8; 16] = rand::thread_rng().gen();
+        let node_id: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        match fs::write(&path, &node_id) {
+            Ok(_) => (),
+            Err(err) => panic!("error writing node id to {}: {}", path, err),
+        };
+
+        node_id
+    }
+}
@@ -4,13 +4,22 @@ extern crate bincode;
 extern crate bs58;
 extern crate serde;
 
+mod amount;
 mod block;
 mod blockchain;
+mod bootstrap;
 mod cli;
+mod coin_selection;
+mod compact;
+mod datadir;
 mod merkle_tree;
+mod muhash;
+mod policy;
 mod proofofwork;
 mod server;
+mod storage;
 mod transaction;
+mod utxo_cache;
 mod utxo_set;
 mod wallet;
 mod wallets;
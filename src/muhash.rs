@@ -0,0 +1,238 @@
+use crypto::chacha20::ChaCha20;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+
+// Elements of the multiplicative group modulo the safe prime 2^3072 - 1103717,
+// as little-endian 64 bit limbs. A group this size keeps generalized birthday
+// attacks on the multiset hash out of reach, unlike a 256 bit field.
+const LIMBS: usize = 48;
+const BYTES: usize = LIMBS * 8;
+// 2^3072 minus the prime
+const PRIME_DIFF: u64 = 1_103_717;
+
+type Element = [u64; LIMBS];
+
+fn one() -> Element {
+    let mut element = [0; LIMBS];
+    element[0] = 1;
+    element
+}
+
+fn is_ge_prime(a: &Element) -> bool {
+    a[1..].iter().all(|limb| *limb == u64::MAX) && a[0] >= PRIME_DIFF.wrapping_neg()
+}
+
+// Adds small to a, returning what carries out of the top limb
+fn add_small(a: &mut [u64], small: u128) -> u128 {
+    let mut carry = small;
+
+    for limb in a.iter_mut() {
+        if carry == 0 {
+            break;
+        }
+
+        let v = *limb as u128 + (carry as u64) as u128;
+        *limb = v as u64;
+        carry = (carry >> 64) + (v >> 64);
+    }
+
+    carry
+}
+
+// a - p is a + (2^3072 - p) with the 2^3072 bit dropped
+fn canonicalize(mut a: Element) -> Element {
+    if is_ge_prime(&a) {
+        add_small(&mut a, PRIME_DIFF as u128);
+    }
+
+    a
+}
+
+// 2^3072 = PRIME_DIFF modulo the prime, so the high half folds back in
+// multiplied by PRIME_DIFF
+fn mul(a: &Element, b: &Element) -> Element {
+    let mut wide = [0u64; 2 * LIMBS];
+
+    for i in 0..LIMBS {
+        let mut carry = 0u128;
+
+        for j in 0..LIMBS {
+            let v = wide[i + j] as u128 + a[i] as u128 * b[j] as u128 + carry;
+            wide[i + j] = v as u64;
+            carry = v >> 64;
+        }
+
+        wide[i + LIMBS] = carry as u64;
+    }
+
+    let mut r = [0u64; LIMBS];
+    let mut carry = 0u128;
+
+    for i in 0..LIMBS {
+        let v = wide[i] as u128 + wide[i + LIMBS] as u128 * PRIME_DIFF as u128 + carry;
+        r[i] = v as u64;
+        carry = v >> 64;
+    }
+
+    while carry > 0 {
+        carry = add_small(&mut r, carry * PRIME_DIFF as u128);
+    }
+
+    canonicalize(r)
+}
+
+// Fermat's little theorem, a^(p - 2) is the inverse of a. The exponent is
+// walked four bits at a time against a table of a^0 to a^15.
+fn inverse(a: &Element) -> Element {
+    let mut exponent = [u64::MAX; LIMBS];
+    exponent[0] = (PRIME_DIFF + 2).wrapping_neg();
+
+    let mut powers = vec![one()];
+
+    for i in 1..16 {
+        powers.push(mul(&powers[i - 1], a));
+    }
+
+    let mut result = one();
+
+    for i in (0..LIMBS).rev() {
+        for shift in (0..16).rev() {
+            for _ in 0..4 {
+                result = mul(&result, &result);
+            }
+
+            let window = (exponent[i] >> (shift * 4)) & 0xf;
+            result = mul(&result, &powers[window as usize]);
+        }
+    }
+
+    result
+}
+
+// The SHA-256 of the data keys ChaCha20, whose keystream is the element
+fn to_element(data: &[u8]) -> Element {
+    let mut hasher = Sha256::new();
+    let mut key = [0u8; 32];
+    hasher.input(data);
+    hasher.result(&mut key);
+
+    let mut stream = [0u8; BYTES];
+    ChaCha20::new(&key, &[0; 8]).process(&[0; BYTES], &mut stream);
+
+    let mut element = [0u64; LIMBS];
+
+    for (i, limb) in element.iter_mut().enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&stream[i * 8..i * 8 + 8]);
+        *limb = u64::from_le_bytes(bytes);
+    }
+
+    let element = canonicalize(element);
+
+    if element == [0; LIMBS] {
+        return one();
+    }
+
+    element
+}
+
+fn to_limbs(element: &Element) -> Vec<u64> {
+    element.to_vec()
+}
+
+fn from_limbs(limbs: &[u64]) -> Element {
+    let mut element = [0; LIMBS];
+    element.copy_from_slice(limbs);
+    element
+}
+
+// Multiset hash: the product of the hashes of all inserted items divided by
+// the product of the removed ones, so the result doesn't depend on order and
+// can be updated as items come and go.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MuHash {
+    numerator: Vec<u64>,
+    denominator: Vec<u64>,
+}
+
+impl MuHash {
+    pub fn new() -> MuHash {
+        MuHash {
+            numerator: to_limbs(&one()),
+            denominator: to_limbs(&one()),
+        }
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        let numerator = mul(&from_limbs(&self.numerator), &to_element(data));
+        self.numerator = to_limbs(&numerator);
+    }
+
+    pub fn remove(&mut self, data: &[u8]) {
+        let denominator = mul(&from_limbs(&self.denominator), &to_element(data));
+        self.denominator = to_limbs(&denominator);
+    }
+
+    pub fn finalize(&self) -> String {
+        let value = mul(
+            &from_limbs(&self.numerator),
+            &inverse(&from_limbs(&self.denominator)),
+        );
+        let mut bytes = Vec::new();
+
+        for limb in value.iter() {
+            bytes.extend_from_slice(&limb.to_le_bytes());
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.input(&bytes);
+        hasher.result_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order_doesnt_matter() {
+        let mut a = MuHash::new();
+        let mut b = MuHash::new();
+
+        for item in [&b"x"[..], b"y", b"z"].iter() {
+            a.insert(item);
+        }
+
+        for item in [&b"z"[..], b"x", b"y"].iter() {
+            b.insert(item);
+        }
+
+        assert_eq!(a.finalize(), b.finalize());
+    }
+
+    #[test]
+    fn remove_undoes_insert() {
+        let mut muhash = MuHash::new();
+        muhash.insert(b"x");
+        let expected = muhash.finalize();
+
+        muhash.insert(b"y");
+        assert_ne!(muhash.finalize(), expected);
+
+        muhash.remove(b"y");
+        assert_eq!(muhash.finalize(), expected);
+    }
+
+    #[test]
+    fn reduces_modulo_the_prime() {
+        let mut prime = [u64::MAX; LIMBS];
+        prime[0] = PRIME_DIFF.wrapping_neg();
+        assert_eq!(canonicalize(prime), [0; LIMBS]);
+
+        let mut minus_one = prime;
+        minus_one[0] -= 1;
+        assert_eq!(mul(&minus_one, &minus_one), one());
+        assert_eq!(mul(&minus_one, &inverse(&minus_one)), one());
+    }
+}
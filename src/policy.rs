@@ -0,0 +1,391 @@
+use crate::transaction::Lock;
+use crate::wallet::{self, Wallet};
+
+use std::fmt;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 65;
+const STACK_ITEM_OVERHEAD: usize = 8;
+// Policies come from peers inside witnesses, these bound the bytes decoded and
+// how deep the checks and evaluation recurse
+const MAX_POLICY_SIZE: u64 = 10_000;
+const MAX_POLICY_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Policy {
+    Key(Vec<u8>),
+    After(i32),
+    And(Vec<Policy>),
+    Or(Vec<Policy>),
+    Threshold(usize, Vec<Policy>),
+}
+
+impl Policy {
+    pub fn parse(policy: &str) -> Result<Policy, String> {
+        let chars: Vec<char> = policy.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut parser = Parser {
+            chars,
+            pos: 0,
+            depth: 0,
+        };
+        let policy = parser.parse_policy()?;
+
+        if parser.pos != parser.chars.len() {
+            return Err(format!("unexpected input at position {}", parser.pos));
+        }
+
+        policy.check(0)?;
+
+        if policy.encode().len() as u64 > MAX_POLICY_SIZE {
+            return Err(format!("policy is longer than {} bytes", MAX_POLICY_SIZE));
+        }
+
+        Ok(policy)
+    }
+
+    fn check(&self, depth: usize) -> Result<(), String> {
+        if depth > MAX_POLICY_DEPTH {
+            return Err(format!("policy is nested deeper than {}", MAX_POLICY_DEPTH));
+        }
+
+        match self {
+            Policy::Key(key) if key.len() != PUBLIC_KEY_LEN => Err(format!(
+                "pk() expects a {} byte public key, got {}",
+                PUBLIC_KEY_LEN,
+                key.len()
+            )),
+            Policy::Key(_) => Ok(()),
+            Policy::After(height) if *height <= 0 => {
+                Err(String::from("after() expects a positive block height"))
+            }
+            Policy::After(_) => Ok(()),
+            Policy::And(subs) | Policy::Or(subs) if subs.len() < 2 => {
+                Err(String::from("and() and or() expect at least two arguments"))
+            }
+            Policy::Threshold(k, subs) if *k == 0 || *k > subs.len() => Err(format!(
+                "thresh() expects 1 to {} required arguments, got {}",
+                subs.len(),
+                k
+            )),
+            Policy::And(subs) | Policy::Or(subs) | Policy::Threshold(_, subs) => {
+                subs.iter().try_for_each(|sub| sub.check(depth + 1))
+            }
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("error serializing Policy")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Policy> {
+        let policy: Policy = bincode::config()
+            .limit(MAX_POLICY_SIZE)
+            .deserialize(bytes)
+            .ok()?;
+        policy.check(0).ok()?;
+        Some(policy)
+    }
+
+    pub fn compile(&self) -> Lock {
+        Lock::PolicyHash(Wallet::hash_pub_key(&self.encode()))
+    }
+
+    pub fn address(&self) -> String {
+        Wallet::address_from_hash(
+            wallet::POLICY_VERSION,
+            &Wallet::hash_pub_key(&self.encode()),
+        )
+    }
+
+    // Public keys of every pk() in the order the witness stack lists them
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Policy::Key(key) => vec![&key[..]],
+            Policy::After(_) => Vec::new(),
+            Policy::And(subs) | Policy::Or(subs) | Policy::Threshold(_, subs) => {
+                subs.iter().flat_map(|sub| sub.keys()).collect()
+            }
+        }
+    }
+
+    pub fn max_after(&self) -> i32 {
+        match self {
+            Policy::Key(_) => 0,
+            Policy::After(height) => *height,
+            Policy::And(subs) | Policy::Or(subs) | Policy::Threshold(_, subs) => {
+                subs.iter().map(|sub| sub.max_after()).max().unwrap_or(0)
+            }
+        }
+    }
+
+    // Evaluates the policy given which pk() leaves carry a valid signature
+    pub fn is_satisfied(&self, signed: &[bool], lock_time: i32) -> bool {
+        self.evaluate(signed, &mut 0, lock_time)
+    }
+
+    fn evaluate(&self, signed: &[bool], leaf: &mut usize, lock_time: i32) -> bool {
+        match self {
+            Policy::Key(_) => {
+                *leaf += 1;
+                signed[*leaf - 1]
+            }
+            Policy::After(height) => lock_time >= *height,
+            // Every branch is evaluated before combining, short-circuiting would
+            // leave `leaf` behind the pk() leaves of the branches it skipped
+            Policy::And(subs) => subs
+                .iter()
+                .map(|sub| sub.evaluate(signed, leaf, lock_time))
+                .collect::<Vec<bool>>()
+                .into_iter()
+                .all(|ok| ok),
+            Policy::Or(subs) => subs
+                .iter()
+                .map(|sub| sub.evaluate(signed, leaf, lock_time))
+                .collect::<Vec<bool>>()
+                .into_iter()
+                .any(|ok| ok),
+            Policy::Threshold(k, subs) => {
+                subs.iter()
+                    .filter(|sub| sub.evaluate(signed, leaf, lock_time))
+                    .count()
+                    >= *k
+            }
+        }
+    }
+
+    // Cheapest way to satisfy the policy using only the keys for which `available`
+    // returns true, as the pk() leaves to sign. None if it can't be satisfied.
+    pub fn plan<F: Fn(&[u8]) -> bool>(&self, available: &F, lock_time: i32) -> Option<Vec<usize>> {
+        self.plan_from(available, lock_time, &mut 0)
+    }
+
+    fn plan_from<F: Fn(&[u8]) -> bool>(
+        &self,
+        available: &F,
+        lock_time: i32,
+        leaf: &mut usize,
+    ) -> Option<Vec<usize>> {
+        match self {
+            Policy::Key(key) => {
+                *leaf += 1;
+
+                if available(key) {
+                    Some(vec![*leaf - 1])
+                } else {
+                    None
+                }
+            }
+            Policy::After(height) => {
+                if lock_time >= *height {
+                    Some(Vec::new())
+                } else {
+                    None
+                }
+            }
+            Policy::And(subs) => {
+                let plans: Vec<Option<Vec<usize>>> = subs
+                    .iter()
+                    .map(|sub| sub.plan_from(available, lock_time, leaf))
+                    .collect();
+                let plans: Option<Vec<Vec<usize>>> = plans.into_iter().collect();
+                Some(plans?.concat())
+            }
+            Policy::Or(subs) => subs
+                .iter()
+                .map(|sub| sub.plan_from(available, lock_time, leaf))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .flatten()
+                .min_by_key(|plan| plan.len()),
+            Policy::Threshold(k, subs) => {
+                let mut plans: Vec<Vec<usize>> = subs
+                    .iter()
+                    .map(|sub| sub.plan_from(available, lock_time, leaf))
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .flatten()
+                    .collect();
+
+                if plans.len() < *k {
+                    return None;
+                }
+
+                plans.sort_by_key(|plan| plan.len());
+                Some(plans[..*k].concat())
+            }
+        }
+    }
+
+    pub fn analyze(&self) -> Analysis {
+        let all = |_: &[u8]| true;
+        let min_signatures = self
+            .plan(&all, self.max_after())
+            .map(|plan| plan.len())
+            .unwrap_or(0);
+        let keys = self.keys().len();
+        let base = STACK_ITEM_OVERHEAD + STACK_ITEM_OVERHEAD + self.encode().len();
+
+        Analysis {
+            keys,
+            min_signatures,
+            min_witness_size: base + keys * STACK_ITEM_OVERHEAD + min_signatures * SIGNATURE_LEN,
+            timelock: self.max_after(),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |subs: &[Policy]| {
+            subs.iter()
+                .map(|sub| sub.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+
+        match self {
+            Policy::Key(key) => {
+                let key: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, "pk({})", key)
+            }
+            Policy::After(height) => write!(f, "after({})", height),
+            Policy::And(subs) => write!(f, "and({})", join(subs)),
+            Policy::Or(subs) => write!(f, "or({})", join(subs)),
+            Policy::Threshold(k, subs) => write!(f, "thresh({},{})", k, join(subs)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Analysis {
+    pub keys: usize,
+    pub min_signatures: usize,
+    pub min_witness_size: usize,
+    pub timelock: i32,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn parse_policy(&mut self) -> Result<Policy, String> {
+        let name = self.take_while(|c| c.is_ascii_alphabetic());
+        self.expect('(')?;
+
+        let policy = match &name[..] {
+            "pk" => {
+                let key = self.take_while(|c| c.is_ascii_hexdigit());
+                Policy::Key(from_hex(&key)?)
+            }
+            "after" => Policy::After(self.parse_number()? as i32),
+            "and" => Policy::And(self.parse_list()?),
+            "or" => Policy::Or(self.parse_list()?),
+            "thresh" => {
+                let k = self.parse_number()?;
+                self.expect(',')?;
+                Policy::Threshold(k, self.parse_list()?)
+            }
+            name => return Err(format!("unknown fragment '{}'", name)),
+        };
+
+        self.expect(')')?;
+        Ok(policy)
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<Policy>, String> {
+        if self.depth == MAX_POLICY_DEPTH {
+            return Err(format!("policy is nested deeper than {}", MAX_POLICY_DEPTH));
+        }
+
+        self.depth += 1;
+        let mut subs = vec![self.parse_policy()?];
+
+        while self.peek() == Some(',') {
+            self.pos += 1;
+            subs.push(self.parse_policy()?);
+        }
+
+        self.depth -= 1;
+        Ok(subs)
+    }
+
+    fn parse_number(&mut self) -> Result<usize, String> {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        digits
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n <= i32::MAX as usize)
+            .ok_or_else(|| format!("expected a number at position {}", self.pos))
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> String {
+        let start = self.pos;
+
+        while self.peek().is_some_and(&f) {
+            self.pos += 1;
+        }
+
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(format!("expected '{}' at position {}", c, self.pos));
+        }
+
+        self.pos += 1;
+        Ok(())
+    }
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 != 0 {
+        return Err(format!("odd length hex string '{}'", hex));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|err| err.to_string()))
+        .collect()
+}
+
+pub struct Satisfier {
+    policy: Policy,
+    keys: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Satisfier {
+    pub fn new(policy: Policy) -> Satisfier {
+        Satisfier {
+            policy,
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn add_key(&mut self, public_key: &[u8], pkcs8_bytes: &[u8]) {
+        self.keys.push((public_key.to_vec(), pkcs8_bytes.to_vec()));
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    pub fn pkcs8_bytes(&self, public_key: &[u8]) -> Option<&[u8]> {
+        self.keys
+            .iter()
+            .find(|(key, _)| &key[..] == public_key)
+            .map(|(_, pkcs8_bytes)| &pkcs8_bytes[..])
+    }
+
+    pub fn plan(&self, lock_time: i32) -> Option<Vec<usize>> {
+        self.policy
+            .plan(&|key: &[u8]| self.pkcs8_bytes(key).is_some(), lock_time)
+    }
+}
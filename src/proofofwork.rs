@@ -1,4 +1,4 @@
-use crate::block::Block;
+use crate::block::{Block, BlockHeader};
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
@@ -8,15 +8,21 @@ const MAX_NONCE: u64 = std::u64::MAX;
 const TARGET_BYTE: i32 = 61;
 
 #[derive(Debug)]
-pub struct ProofOfWork<'a> {
-    block: &'a Block,
+pub struct ProofOfWork {
+    header: BlockHeader,
     target: String,
 }
 
-impl<'a> ProofOfWork<'a> {
+impl ProofOfWork {
     pub fn new(block: &Block) -> ProofOfWork {
+        ProofOfWork::for_header(&block.header())
+    }
+
+    // The header commits to the transactions through its merkle roots, so its
+    // work checks without the body
+    pub fn for_header(header: &BlockHeader) -> ProofOfWork {
         ProofOfWork {
-            block,
+            header: header.clone(),
             target: (0..64)
                 .rev()
                 .map(|x| if x == TARGET_BYTE { '1' } else { '0' })
@@ -26,10 +32,12 @@ impl<'a> ProofOfWork<'a> {
 
     fn prepare_data(&self, nonce: u64) -> String {
         format!(
-            "{}{}{}{}{}",
-            self.block.prev_block_hash(),
-            self.block.hash_transactions(),
-            self.block.timestamp(),
+            "{}{}{}{}{}{}{}",
+            self.header.prev_block_hash(),
+            self.header.tx_root(),
+            self.header.witness_root(),
+            self.header.utxo_commitment(),
+            self.header.timestamp(),
             self.target,
             nonce
         )
@@ -56,11 +64,16 @@ impl<'a> ProofOfWork<'a> {
         (nonce, hash)
     }
 
+    // The block hash has to be the one its content and nonce give and below the target
     pub fn validate(&self) -> bool {
-        let data = self.prepare_data(self.block.nonce());
+        let data = self.prepare_data(self.header.nonce());
         let mut hasher = Sha256::new();
         hasher.input_str(&data[..]);
         let hash = hasher.result_str();
+
+        if hash != self.header.hash() {
+            return false;
+        }
         match hash.cmp(&self.target) {
             Ordering::Less => true,
             _ => false,
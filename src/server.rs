@@ -1,23 +1,30 @@
 use crate::block::Block;
 use crate::blockchain::Blockchain;
+use crate::datadir::DataDir;
 use crate::transaction::Transaction;
-use crate::utxo_set::UTXOSet;
+use crate::utxo_set::{self, UTXOSet};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{Read, Write};
 use std::net::TcpListener;
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use std::cell::RefCell;
 
 const NODE_VERSION: i32 = 1;
+// How long validate_snapshot waits before looking for a missing block again
+const SNAPSHOT_BLOCK_WAIT: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Version {
     version: i32,
     best_height: i32,
     addr_from: String,
+    node_id: String,
+    // Blocks up to this height can't be served, -1 for a node with the whole chain
+    pruned_height: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,6 +47,14 @@ struct GetData {
     id: String,
 }
 
+// Answer to getdata for an item the node doesn't have, e.g. a pruned block
+#[derive(Debug, Serialize, Deserialize)]
+struct NotFound {
+    addr_from: String,
+    kind: String,
+    id: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TxWrapper {
     pub addr_from: String,
@@ -53,7 +68,10 @@ pub struct Server {
     known_nodes: Mutex<RefCell<Vec<String>>>,
     blocks_in_transit: Mutex<RefCell<Vec<String>>>,
     mempool: Mutex<RefCell<HashMap<String, Transaction>>>,
-    bc: Mutex<RefCell<Blockchain>>
+    bc: Mutex<RefCell<Blockchain>>,
+    // One set and one cache for the whole node, locked after bc when both are
+    // needed and flushed after every block, as the node never drops it
+    utxo_set: Mutex<RefCell<UTXOSet>>,
 }
 
 impl Server {
@@ -144,6 +162,20 @@ impl Server {
         self.send_data(address, &request);
     }
 
+    fn send_not_found(&self, address: &str, kind: &str, id: &str) {
+        let cmd = b"notfound\n";
+        let data = NotFound{
+            addr_from: self.node_address.clone(),
+            kind: kind.to_string(),
+            id: id.to_string(),
+        };
+        let payload = bincode::serialize(&data).unwrap();
+        let mut request = Vec::new();
+        request.extend(cmd);
+        request.extend(payload);
+        self.send_data(address, &request);
+    }
+
     pub fn send_tx(&self, address: &str, tx: &Transaction) {
         let cmd = b"tx\n";
         let data = TxWrapper{
@@ -159,10 +191,13 @@ impl Server {
 
     fn send_version(&self, address: &str, height: i32) {
         let cmd = b"version\n";
+        let pruned_height = self.bc.lock().unwrap().borrow().pruned_height().unwrap_or(-1);
         let version = Version{
             version: NODE_VERSION,
             best_height: height,
-            addr_from: self.node_address.clone()
+            addr_from: self.node_address.clone(),
+            node_id: self.node_id.clone(),
+            pruned_height,
         };
         let payload = bincode::serialize(&version).unwrap();
         let mut request = Vec::new();
@@ -182,17 +217,55 @@ impl Server {
         let payload: BlockWrapper = bincode::deserialize(request).unwrap();
         let block = Block::deserialize(payload.block);
         println!("Received a new block!");
-
-        self.bc.lock().unwrap().borrow_mut().add_block(&block);
-        println!("Added block: {}", block.hash());
+        self.connect_block(&block);
 
         if self.blocks_in_transit.lock().unwrap().borrow().len() > 0 {
             let block_hash = self.blocks_in_transit.lock().unwrap().borrow()[0].clone();
             self.send_get_data(&payload.addr_from, "block", &block_hash);
             self.blocks_in_transit.lock().unwrap().borrow_mut().remove(0);
-        } else {
-            let mut utxo_set = UTXOSet::new(&self.node_id);
-            utxo_set.reindex(&self.node_id, &mut self.bc.lock().unwrap().borrow_mut());
+        }
+    }
+
+    // Appends a block on top of the tip and applies it to the UTXO set
+    fn connect_block(&self, block: &Block) {
+        let bc = self.bc.lock().unwrap();
+        let mut bc = bc.borrow_mut();
+
+        let utxo_set = self.utxo_set.lock().unwrap();
+        let mut utxo_set = utxo_set.borrow_mut();
+
+        if bc.has_header(block.hash()) {
+            // Bodies below a loaded snapshot are downloaded for validate_snapshot
+            if !bc.has_block(block.hash()) && utxo_set.pending_snapshot().is_some() {
+                match bc.add_block_body(block) {
+                    Ok(_) => println!("Added body of block: {}", block.hash()),
+                    Err(err) => println!("Ignoring block {}: {}", block.hash(), err),
+                }
+            } else {
+                println!("Block {} is already known", block.hash());
+            }
+            return
+        }
+
+        if let Err(err) = bc.validate_block(block, &mut utxo_set) {
+            println!("Ignoring block {}: {}", block.hash(), err);
+            return
+        }
+
+        bc.add_block(block);
+        utxo_set.update(block);
+        utxo_set.flush();
+        println!("Added block: {}", block.hash());
+        Server::prune(&mut bc, &mut utxo_set);
+    }
+
+    fn prune(bc: &mut Blockchain, utxo_set: &mut UTXOSet) {
+        let pruned = bc.prune(utxo_set);
+
+        if let Some(height) = bc.pruned_height() {
+            if pruned > 0 {
+                println!("Pruned {} blocks, blocks up to height {} are gone", pruned, height);
+            }
         }
     }
 
@@ -228,7 +301,13 @@ impl Server {
 
     fn handle_get_blocks(&self, request: &[u8]) {
         let addr_from = String::from_utf8_lossy(request);
-        let blocks = self.bc.lock().unwrap().borrow_mut().get_block_hashes();
+        let blocks = {
+            let bc = self.bc.lock().unwrap();
+            let mut bc = bc.borrow_mut();
+            let block_hashes = bc.get_block_hashes();
+            // Only blocks that weren't pruned can be served
+            block_hashes.into_iter().filter(|hash| bc.has_block(hash)).collect()
+        };
         self.send_inv(&addr_from, "block", blocks);
     }
 
@@ -237,11 +316,27 @@ impl Server {
 
         match payload.kind.as_ref() {
             "block" => {
-                let block = self.bc.lock().unwrap().borrow_mut().get_block(&payload.id);
-                self.send_block(&payload.addr_from, &block);
+                let block = {
+                    let bc = self.bc.lock().unwrap();
+                    let mut bc = bc.borrow_mut();
+
+                    if bc.has_block(&payload.id) {
+                        Some(bc.get_block(&payload.id))
+                    } else {
+                        None
+                    }
+                };
+
+                match block {
+                    Some(block) => self.send_block(&payload.addr_from, &block),
+                    None => self.send_not_found(&payload.addr_from, "block", &payload.id),
+                }
             },
             "tx" => {
-                let tx = self.mempool.lock().unwrap().borrow().get(&payload.id).unwrap().clone();
+                let tx = match self.mempool.lock().unwrap().borrow().get(&payload.id) {
+                    Some(tx) => tx.clone(),
+                    None => return self.send_not_found(&payload.addr_from, "tx", &payload.id),
+                };
                 self.send_tx(&payload.addr_from, &tx);
                 // self.mempool.lock().unwrap().borrow_mut().remove(tx.id());
             },
@@ -249,6 +344,16 @@ impl Server {
         }
     }
 
+    fn handle_not_found(&self, request: &[u8]) {
+        let payload: NotFound = bincode::deserialize(request).unwrap();
+        println!("{} doesn't have {} {}", payload.addr_from, payload.kind, payload.id);
+
+        // The rest of the blocks can't be connected without this one
+        if payload.kind == "block" {
+            self.blocks_in_transit.lock().unwrap().borrow_mut().clear();
+        }
+    }
+
     fn handle_tx(&self, request: &[u8]) {
         let payload: TxWrapper = bincode::deserialize(request).unwrap();
         let tx = Transaction::deserialize(payload.tx);
@@ -263,23 +368,34 @@ impl Server {
         } else {
             if self.mempool.lock().unwrap().borrow().len() >= 2 && !self.mining_address.is_empty() {
                 loop {
-                    let mut txs = Vec::new();
-
-                    for (_, v) in self.mempool.lock().unwrap().borrow().iter() {
-                        if self.bc.lock().unwrap().borrow_mut().verify_transaction(v) {
-                            txs.push(v.clone());
+                    let mut txs = vec![Transaction::new_coin_base_tx(&self.mining_address, "")];
+                    let candidates: Vec<Transaction> = self.mempool.lock().unwrap().borrow().values().cloned().collect();
+                    // Held until the block is connected, so no other block lands in between
+                    let bc = self.bc.lock().unwrap();
+                    let mut bc = bc.borrow_mut();
+                    let utxo_set = self.utxo_set.lock().unwrap();
+                    let mut utxo_set = utxo_set.borrow_mut();
+
+                    for tx in candidates {
+                        txs.push(tx);
+
+                        if let Err(err) = bc.validate_transactions(&txs, &mut utxo_set) {
+                            println!("Skipping transaction: {}", err);
+                            txs.pop();
                         }
                     }
 
-                    if txs.is_empty() {
+                    if txs.len() == 1 {
                         println!("All transactions are invalid! Waiting for new ones...");
                         return
                     }
 
-                    txs.push(Transaction::new_coin_base_tx(&self.mining_address, ""));
-                    let new_block = self.bc.lock().unwrap().borrow_mut().mine_block(txs.clone());
-                    let mut utxo_set = UTXOSet::new(&self.node_id);
-                    utxo_set.reindex(&self.node_id, &mut self.bc.lock().unwrap().borrow_mut());
+                    let new_block = bc.mine_block(txs.clone(), &mut utxo_set);
+                    utxo_set.update(&new_block);
+                    utxo_set.flush();
+                    Server::prune(&mut bc, &mut utxo_set);
+                    drop(utxo_set);
+                    drop(bc);
 
                     for tx in txs {
                         self.mempool.lock().unwrap().borrow_mut().remove(tx.id());
@@ -301,11 +417,28 @@ impl Server {
 
     fn handle_version(&self, request: &[u8]) {
         let payload: Version = bincode::deserialize(request).unwrap();
+
+        // Another address of this very node, e.g. it was started on a new port
+        if payload.node_id == self.node_id {
+            return;
+        }
+
         let my_best_height = self.bc.lock().unwrap().borrow_mut().get_best_height();
         let foreigner_best_height = payload.best_height;
-
-        if my_best_height < foreigner_best_height {
-            self.send_get_blocks(&payload.addr_from);
+        // Blocks below a loaded snapshot may still be missing
+        let pending_snapshot = self.utxo_set.lock().unwrap().borrow_mut().pending_snapshot().is_some();
+
+        if my_best_height < foreigner_best_height
+            || (pending_snapshot && my_best_height == foreigner_best_height)
+        {
+            if payload.pruned_height > my_best_height {
+                println!(
+                    "{} has pruned blocks up to height {}, can't sync from it",
+                    payload.addr_from, payload.pruned_height
+                );
+            } else {
+                self.send_get_blocks(&payload.addr_from);
+            }
         } else {
             self.send_version(&payload.addr_from, my_best_height);
         }
@@ -355,6 +488,7 @@ impl Server {
             "addr" => self.handle_addr(&request),
             "block" => self.handle_block(&request),
             "inv" => self.handle_inv(&request),
+            "notfound" => self.handle_not_found(&request),
             "getblocks" => self.handle_get_blocks(&request),
             "getdata" => self.handle_get_data(&request),
             "tx" => self.handle_tx(&request),
@@ -363,8 +497,8 @@ impl Server {
         }
     }
 
-    pub fn start(node_id: &str, miner_address: &str) {
-        let node_address = format!("127.0.0.1:{}", node_id);
+    pub fn start(data_dir: &DataDir, node_id: &str, port: &str, miner_address: &str) {
+        let node_address = format!("127.0.0.1:{}", port);
         let server = Arc::new(Server {
             node_id: node_id.to_string(),
             node_address,
@@ -372,15 +506,40 @@ impl Server {
             known_nodes: Mutex::new(RefCell::new(vec!["127.0.0.1:3000".to_string()])),
             blocks_in_transit: Mutex::new(RefCell::new(Vec::new())),
             mempool: Mutex::new(RefCell::new(HashMap::new())),
-            bc: Mutex::new(RefCell::new(Blockchain::new(node_id))),
+            bc: Mutex::new(RefCell::new(Blockchain::new(data_dir))),
+            utxo_set: Mutex::new(RefCell::new(UTXOSet::new(data_dir))),
         });
 
+        let pending_snapshot = {
+            let bc = server.bc.lock().unwrap();
+            let mut bc = bc.borrow_mut();
+            let utxo_set = server.utxo_set.lock().unwrap();
+            let mut utxo_set = utxo_set.borrow_mut();
+
+            if utxo_set.recover(&mut bc) {
+                println!("Recovered the UTXO set after an interrupted commit");
+            }
+
+            Server::prune(&mut bc, &mut utxo_set);
+
+            utxo_set.pending_snapshot()
+        };
+
+        if let Some((block_hash, content_hash)) = pending_snapshot {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                server.validate_snapshot(&block_hash, &content_hash);
+            });
+        }
+
+        // Listening before the version goes out, so the reply isn't refused
+        let listener = TcpListener::bind(&server.node_address).unwrap();
+
         if server.node_address != server.known_nodes.lock().unwrap().borrow()[0] {
             let height = server.bc.lock().unwrap().borrow_mut().get_best_height();
             server.send_version(&server.known_nodes.lock().unwrap().borrow()[0], height);
         }
 
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", node_id)).unwrap();
         for stream in listener.incoming() {
             let server = Arc::clone(&server);
             thread::spawn(move || {
@@ -390,6 +549,51 @@ impl Server {
         }
     }
 
+    // Replays the chain up to a loaded snapshot and checks it produced the same
+    // set. Blocks not downloaded yet are waited for, the node syncs meanwhile.
+    fn validate_snapshot(&self, block_hash: &str, content_hash: &str) {
+        println!("Validating UTXO snapshot at block {} in the background", block_hash);
+        let mut block_hashes = Vec::new();
+        let mut hash = block_hash.to_string();
+
+        while !hash.is_empty() {
+            let header = self.bc.lock().unwrap().borrow_mut().get_header(&hash);
+            block_hashes.push(hash);
+            hash = header.prev_block_hash().to_string();
+        }
+
+        let mut utxo = BTreeMap::new();
+
+        for hash in block_hashes.iter().rev() {
+            let block = loop {
+                {
+                    let bc = self.bc.lock().unwrap();
+                    let mut bc = bc.borrow_mut();
+
+                    if bc.has_block(hash) {
+                        break bc.get_block(hash);
+                    }
+                }
+
+                thread::sleep(SNAPSHOT_BLOCK_WAIT);
+            };
+            utxo_set::apply_block(&mut utxo, &block);
+        }
+
+        let bc = self.bc.lock().unwrap();
+        let mut bc = bc.borrow_mut();
+        let utxo_set = self.utxo_set.lock().unwrap();
+        let mut utxo_set = utxo_set.borrow_mut();
+
+        if utxo_set::content_hash(&utxo) == content_hash {
+            utxo_set.clear_pending_snapshot();
+            println!("UTXO snapshot at block {} is valid", block_hash);
+        } else {
+            println!("UTXO snapshot at block {} doesn't match the chain, reindexing", block_hash);
+            utxo_set.reindex(&mut bc);
+        }
+    }
+
     fn is_known_node(&self, address: &str) -> bool {
         for node in self.known_nodes.lock().unwrap().borrow().iter() {
             if node == address {
@@ -0,0 +1,346 @@
+use crate::datadir::DataDir;
+
+use persy::{Config, Persy};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(Debug)]
+pub struct StorageError(String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(err: sled::Error) -> StorageError {
+        StorageError(err.to_string())
+    }
+}
+
+// Segment typedb keeps its records in
+const LEGACY_SEGMENT: &str = "tdb";
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push(BatchOp::Put(key.to_vec(), value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push(BatchOp::Delete(key.to_vec()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+// Ordered key-value engine underneath the chain, UTXO set and wallet stores
+pub trait Storage: Send {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+    // Pairs whose key starts with prefix, in key order
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    // Applies every operation of the batch or none of them
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()>;
+    // Returns once every write so far is durable
+    fn flush(&self) -> Result<()>;
+}
+
+// Keeps everything in memory, clones share the same data
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    data: Arc<Mutex<BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.data
+            .lock()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .data
+            .lock()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(key, value) => data.insert(key, value),
+                BatchOp::Delete(key) => data.remove(&key),
+            };
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SledStorage {
+    db: sled::Db,
+}
+
+impl SledStorage {
+    // sled locks a database for as long as the process has it open, and lets
+    // go of the lock only after its background threads stop. Every open of a
+    // path shares one handle instead, so reopening a store never races the lock.
+    pub fn open(path: &str) -> Result<SledStorage> {
+        static OPEN: OnceLock<Mutex<HashMap<String, sled::Db>>> = OnceLock::new();
+        let mut open = OPEN
+            .get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .unwrap();
+
+        if let Some(db) = open.get(path) {
+            return Ok(SledStorage { db: db.clone() });
+        }
+
+        let db = sled::open(path)?;
+        open.insert(path.to_string(), db.clone());
+        Ok(SledStorage { db })
+    }
+}
+
+// The shared handle outlives every store, so each flushes what it wrote on
+// the way out the way closing the database would
+impl Drop for SledStorage {
+    fn drop(&mut self) {
+        if let Err(err) = self.db.flush() {
+            println!("error flushing store: {}", err);
+        }
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.insert(key, value)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+
+        for pair in self.db.scan_prefix(prefix) {
+            let (key, value) = pair?;
+            pairs.push((key.to_vec(), value.to_vec()));
+        }
+
+        Ok(pairs)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(key, value) => sled_batch.insert(key, value),
+                BatchOp::Delete(key) => sled_batch.remove(key),
+            };
+        }
+
+        self.db.apply_batch(sled_batch)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+// Typed view over a storage with string keys and bincode encoded values
+pub struct Store<V> {
+    storage: Box<dyn Storage>,
+    value: PhantomData<V>,
+}
+
+impl<V> Store<V>
+where
+    V: Clone + Serialize + for<'de> Deserialize<'de>,
+{
+    pub fn new(storage: Box<dyn Storage>) -> Store<V> {
+        Store {
+            storage,
+            value: PhantomData,
+        }
+    }
+
+    // Opens NAME.sled in the data directory, or keeps the store in memory when
+    // STORAGE=memory is set. Data of the typedb file NAME.db is imported on first open.
+    pub fn open(name: &str, data_dir: &DataDir) -> Store<V> {
+        if let Ok(backend) = env::var("STORAGE") {
+            match &backend[..] {
+                "memory" => return Store::new(Box::new(MemoryStorage::new())),
+                "sled" => (),
+                _ => panic!("error, unknown storage backend {}", backend),
+            }
+        }
+
+        let path = data_dir.path(name, "sled");
+        let legacy_path = data_dir.path(name, "db");
+        let fresh = !Path::new(&path).exists();
+
+        let storage = match SledStorage::open(&path) {
+            Ok(storage) => storage,
+            Err(err) => panic!("error opening {} store: {}", name, err),
+        };
+        let mut store = Store::new(Box::new(storage));
+
+        if fresh && Path::new(&legacy_path).exists() {
+            store.import_legacy(legacy_path);
+        }
+
+        store
+    }
+
+    // typedb only opens files at 'static paths, so its file is read here the
+    // way typedb loads it: every record holds the whole map, the last one wins
+    fn import_legacy(&mut self, path: String) {
+        let persy =
+            Persy::open(path.as_str(), Config::new()).expect("error opening legacy typedb store");
+        let mut legacy: HashMap<String, V> = HashMap::new();
+
+        if persy
+            .exists_segment(LEGACY_SEGMENT)
+            .expect("error reading legacy typedb store")
+        {
+            for record in persy
+                .scan_records(LEGACY_SEGMENT)
+                .expect("error reading legacy typedb store")
+            {
+                legacy = bincode::deserialize(&record.content)
+                    .expect("error decerializing legacy typedb store");
+            }
+        }
+
+        let pairs: Vec<(String, Option<V>)> = legacy
+            .into_iter()
+            .map(|(key, value)| (key, Some(value)))
+            .collect();
+        let count = pairs.len();
+
+        match self.write_batch(pairs) {
+            Ok(_) => println!("Imported {} records from {}", count, path),
+            Err(err) => panic!("error while importing legacy store {}: {}", path, err),
+        };
+    }
+
+    fn encode(value: &V) -> Vec<u8> {
+        bincode::serialize(value).expect("error serializing store value")
+    }
+
+    fn decode(bytes: &[u8]) -> Result<V> {
+        bincode::deserialize(bytes)
+            .map_err(|err| StorageError(format!("error decerializing store value: {}", err)))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<V>> {
+        match self.storage.get(key.as_bytes())? {
+            Some(bytes) => Ok(Some(Store::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: V) -> Result<()> {
+        self.storage.put(key.as_bytes(), &Store::encode(&value))
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+
+        for (key, _) in self.storage.scan_prefix(&[])? {
+            keys.push(Store::<V>::decode_key(key)?);
+        }
+
+        Ok(keys)
+    }
+
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, V)>> {
+        let mut pairs = Vec::new();
+
+        for (key, value) in self.storage.scan_prefix(prefix.as_bytes())? {
+            pairs.push((Store::<V>::decode_key(key)?, Store::decode(&value)?));
+        }
+
+        Ok(pairs)
+    }
+
+    fn decode_key(key: Vec<u8>) -> Result<String> {
+        String::from_utf8(key)
+            .map_err(|_| StorageError("error, store key is not UTF-8".to_string()))
+    }
+
+    // Puts the pairs with a value and deletes the ones without, atomically
+    pub fn write_batch(&mut self, pairs: Vec<(String, Option<V>)>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+
+        for (key, value) in pairs {
+            match value {
+                Some(value) => batch.put(key.as_bytes(), &Store::encode(&value)),
+                None => batch.delete(key.as_bytes()),
+            }
+        }
+
+        self.storage.write_batch(batch)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.storage.flush()
+    }
+
+    // Hands the storage over to code that keeps its own encoding
+    pub fn into_storage(self) -> Box<dyn Storage> {
+        self.storage
+    }
+}
@@ -1,10 +1,13 @@
+use crate::amount::Amount;
 use crate::blockchain::Blockchain;
+use crate::coin_selection::{CoinSelector, LargestFirst};
+use crate::policy::{Policy, Satisfier};
 use crate::utxo_set::UTXOSet;
 use crate::wallet::{self, Wallet};
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use ring::signature;
+use ring::signature::{self, KeyPair};
 use std::collections::HashMap;
 
 extern crate rand;
@@ -12,13 +15,21 @@ extern crate rand;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 
-const SUBSIDY: i32 = 10;
+pub const SUBSIDY: u64 = 10;
+pub const MAX_DATA_CARRIER_SIZE: usize = 80;
+
+pub const SIGHASH_ALL: u8 = 0x01;
+pub const SIGHASH_NONE: u8 = 0x02;
+pub const SIGHASH_SINGLE: u8 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Transaction {
     id: String,
     v_in: Vec<TXInput>,
     v_out: Vec<TXOutput>,
+    witnesses: Vec<TXWitness>,
+    lock_time: i32,
 }
 
 impl Transaction {
@@ -39,114 +50,449 @@ impl Transaction {
 
         let mut tx = Transaction {
             id: String::new(),
-            v_in: vec![TXInput::new("", -1, Vec::new(), data.as_bytes().to_vec())],
-            v_out: vec![TXOutput::new(SUBSIDY, to)],
+            v_in: vec![TXInput::new("", -1, data.as_bytes().to_vec())],
+            v_out: vec![TXOutput::new(
+                Amount::from_coins(SUBSIDY).expect("error, subsidy exceeds the supply limit"),
+                to,
+            )],
+            witnesses: vec![TXWitness::default()],
+            lock_time: 0,
         };
 
         tx.set_id();
         tx
     }
 
+    // Spends one of the wallet's coins back to it next to the data carrier, so
+    // recording data costs a transaction from the wallet instead of minting
+    pub fn new_data_carrier_tx(
+        wallet: &Wallet,
+        data: &[u8],
+        bc: &mut Blockchain,
+        utxo_set: &mut UTXOSet,
+    ) -> Transaction {
+        let pub_key_hash = Wallet::hash_pub_key(wallet.public_key());
+        let min_amount = Amount::from_base_units(1).expect("error, minimal amount is invalid");
+        let selection = match utxo_set.select_coins(&pub_key_hash, min_amount, &LargestFirst) {
+            Some(selection) => selection,
+            None => panic!("ERROR: Not enough funds"),
+        };
+
+        let inputs: Vec<TXInput> = selection
+            .coins()
+            .iter()
+            .map(|coin| TXInput::new(coin.tx_id(), coin.v_out(), Vec::new()))
+            .collect();
+        let outputs = vec![
+            TXOutput::new_data_carrier(data),
+            TXOutput::new(selection.total(), &wallet.get_address()),
+        ];
+
+        let mut tx = Transaction {
+            id: String::new(),
+            witnesses: vec![TXWitness::default(); inputs.len()],
+            v_in: inputs,
+            v_out: outputs,
+            lock_time: 0,
+        };
+
+        tx.set_id();
+        bc.sign_transaction(&mut tx, wallet.pkcs8_bytes(), utxo_set);
+        tx
+    }
+
+    // Stand-in for a transaction whose block was pruned: the outputs still
+    // unspent at their positions, empty data carriers in place of the rest
+    pub fn from_unspent_outputs(id: &str, outputs: Vec<(usize, TXOutput)>) -> Transaction {
+        let len = outputs.iter().map(|(idx, _)| idx + 1).max().unwrap_or(0);
+        let mut v_out = vec![TXOutput::new_data_carrier(&[]); len];
+
+        for (idx, out) in outputs {
+            v_out[idx] = out;
+        }
+
+        Transaction {
+            id: id.to_string(),
+            v_in: Vec::new(),
+            v_out,
+            witnesses: Vec::new(),
+            lock_time: 0,
+        }
+    }
+
     pub fn new_utxo_tx(
         wallet: &Wallet,
         to: &str,
-        amount: i32,
+        amount: Amount,
         bc: &mut Blockchain,
         utxo_set: &mut UTXOSet,
+        selector: &dyn CoinSelector,
+    ) -> Transaction {
+        Transaction::new_batch_utxo_tx(wallet, &[(to.to_string(), amount)], bc, utxo_set, selector)
+    }
+
+    pub fn new_batch_utxo_tx(
+        wallet: &Wallet,
+        recipients: &[(String, Amount)],
+        bc: &mut Blockchain,
+        utxo_set: &mut UTXOSet,
+        selector: &dyn CoinSelector,
     ) -> Transaction {
-        let mut inputs = Vec::new();
-        let mut outputs = Vec::new();
         let pub_key_hash = Wallet::hash_pub_key(wallet.public_key());
-        let (acc, valid_outputs) = utxo_set.find_spendable_outputs(&pub_key_hash[..], amount);
+        let from = wallet.get_address();
+        let mut tx =
+            Transaction::new_unsigned_tx(&pub_key_hash, &from, recipients, 0, utxo_set, selector);
+        bc.sign_transaction(&mut tx, wallet.pkcs8_bytes(), utxo_set);
+        tx
+    }
 
-        if acc < amount {
-            panic!("ERROR: Not enough funds")
+    pub fn new_policy_tx(
+        satisfier: &Satisfier,
+        recipients: &[(String, Amount)],
+        bc: &mut Blockchain,
+        utxo_set: &mut UTXOSet,
+        selector: &dyn CoinSelector,
+    ) -> Transaction {
+        let policy_hash = satisfier.policy().compile().hash().to_vec();
+        let from = satisfier.policy().address();
+        let lock_time = bc.get_best_height() + 1;
+        let mut tx = Transaction::new_unsigned_tx(
+            &policy_hash,
+            &from,
+            recipients,
+            lock_time,
+            utxo_set,
+            selector,
+        );
+        bc.sign_policy_transaction(&mut tx, satisfier, utxo_set);
+        tx
+    }
+
+    fn new_unsigned_tx(
+        lock_hash: &[u8],
+        from: &str,
+        recipients: &[(String, Amount)],
+        lock_time: i32,
+        utxo_set: &mut UTXOSet,
+        selector: &dyn CoinSelector,
+    ) -> Transaction {
+        if recipients.is_empty() {
+            panic!("ERROR: No recipients given");
         }
 
-        for (idx, outs) in valid_outputs.iter() {
-            for out in outs {
-                let input = TXInput::new(idx, *out, Vec::new(), wallet.public_key().to_vec());
-                inputs.push(input);
+        let mut amount = Amount::ZERO;
+
+        for (to, value) in recipients {
+            if *value == Amount::ZERO {
+                panic!("ERROR: Amount sent to {} must be positive", to);
             }
+
+            amount = match amount.checked_add(*value) {
+                Some(sum) => sum,
+                None => panic!("ERROR: Total amount exceeds the supply limit"),
+            };
         }
 
-        outputs.push(TXOutput::new(amount, to));
-        let from = wallet.get_address();
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        let selection = match utxo_set.select_coins(lock_hash, amount, selector) {
+            Some(selection) => selection,
+            None => panic!("ERROR: Not enough funds"),
+        };
+
+        for coin in selection.coins() {
+            inputs.push(TXInput::new(coin.tx_id(), coin.v_out(), Vec::new()));
+        }
 
-        if acc > amount {
-            outputs.push(TXOutput::new(acc - amount, &from))
+        for (to, value) in recipients {
+            outputs.push(TXOutput::new(*value, to));
+        }
+
+        if selection.change() > Amount::ZERO {
+            outputs.push(TXOutput::new(selection.change(), from))
         }
 
         let mut tx = Transaction {
             id: String::new(),
+            witnesses: vec![TXWitness::default(); inputs.len()],
             v_in: inputs,
             v_out: outputs,
+            lock_time,
         };
 
         tx.set_id();
-        bc.sign_transaction(&mut tx, wallet.pkcs8_bytes());
         tx
     }
 
+    // Bytes saved by the batch, not a fee: the size of paying every recipient in
+    // a transaction of its own, spending the smallest of the batch's inputs and
+    // keeping a change output, less the size of the batch
+    pub fn batch_space_saved(&self, recipients: usize) -> usize {
+        let smallest = self
+            .v_in
+            .iter()
+            .zip(&self.witnesses)
+            .min_by_key(|input| bincode::serialized_size(input).expect("error sizing TXInput"));
+        let (tx_in, witness) = match smallest {
+            Some(input) => input,
+            None => return 0,
+        };
+        let change = match self.v_out.get(recipients).or_else(|| self.v_out.first()) {
+            Some(out) => out,
+            None => return 0,
+        };
+
+        let separate: usize = self.v_out[..recipients.min(self.v_out.len())]
+            .iter()
+            .map(|out| {
+                let tx = Transaction {
+                    id: self.id.clone(),
+                    v_in: vec![tx_in.clone()],
+                    v_out: vec![out.clone(), change.clone()],
+                    witnesses: vec![witness.clone()],
+                    lock_time: self.lock_time,
+                };
+                tx.serialize().len()
+            })
+            .sum();
+        separate.saturating_sub(self.serialize().len())
+    }
+
+    pub fn is_final(&self, height: i32) -> bool {
+        self.lock_time <= height
+    }
+
     pub fn is_coinbase(&self) -> bool {
         self.v_in.len() == 1 && self.v_in[0].tx_id.len() == 0 && self.v_in[0].v_out == -1
     }
 
     pub fn sign(&mut self, pkcs8_bytes: &[u8], prev_txs: &HashMap<String, Transaction>) {
+        self.sign_with(pkcs8_bytes, prev_txs, SIGHASH_ALL);
+    }
+
+    pub fn sign_with(
+        &mut self,
+        pkcs8_bytes: &[u8],
+        prev_txs: &HashMap<String, Transaction>,
+        sig_hash_type: u8,
+    ) {
         if self.is_coinbase() {
             return;
         }
 
-        for tx_in in self.v_in() {
-            if prev_txs[tx_in.tx_id()].id().is_empty() {
-                panic!("error, previous transaction is not correct");
-            }
+        for idx in 0..self.v_in.len() {
+            let prev_tx = &prev_txs[self.v_in[idx].tx_id()];
+            self.sign_input(idx, pkcs8_bytes, prev_tx, sig_hash_type);
         }
+    }
 
-        let mut tx_copy = self.trimmed_copy();
+    pub fn sign_input(
+        &mut self,
+        idx: usize,
+        pkcs8_bytes: &[u8],
+        prev_tx: &Transaction,
+        sig_hash_type: u8,
+    ) {
+        let prev_out = Transaction::spent_output(&self.v_in[idx], prev_tx);
+        let sig = self.sign_digest(idx, prev_out.lock().hash(), pkcs8_bytes, sig_hash_type);
         let key_pair = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8_bytes))
             .expect("error converting bytes to key pair");
-        let mut signatures = Vec::new();
+        self.witnesses[idx] = TXWitness::new(vec![sig, key_pair.public_key().as_ref().to_vec()]);
+    }
 
-        for (i, tx_in) in self.v_in().iter().enumerate() {
-            let prev_tx = &prev_txs[tx_in.tx_id()];
-            let tx_copy_in = &mut tx_copy.mut_v_in()[i];
-            tx_copy_in.set_pub_key(prev_tx.v_out()[tx_in.v_out() as usize].pub_key_hash());
-            tx_copy.set_id();
-            let sig = key_pair.sign(tx_copy.id().as_bytes());
-            signatures.push(sig);
+    // Witness of a policy output is the encoded policy followed by one item per
+    // pk() in the policy, a signature for the keys the satisfier picked and
+    // nothing for the rest.
+    pub fn sign_policy_input(
+        &mut self,
+        idx: usize,
+        prev_tx: &Transaction,
+        satisfier: &Satisfier,
+        sig_hash_type: u8,
+    ) {
+        let prev_out = Transaction::spent_output(&self.v_in[idx], prev_tx);
+        let policy = satisfier.policy();
+
+        if *prev_out.lock() != policy.compile() {
+            panic!("error, output {} isn't locked with the given policy", idx);
         }
 
-        for (i, sig) in signatures.iter().enumerate() {
-            self.v_in[i].set_signature(sig.as_ref());
+        let plan = match satisfier.plan(self.lock_time) {
+            Some(plan) => plan,
+            None => panic!("ERROR: Policy can't be satisfied with the available keys"),
+        };
+        let mut stack = vec![policy.encode()];
+
+        for (leaf, key) in policy.keys().iter().enumerate() {
+            if plan.contains(&leaf) {
+                let pkcs8_bytes = satisfier
+                    .pkcs8_bytes(key)
+                    .expect("error, satisfier has no key for the planned signature");
+                stack.push(self.sign_digest(
+                    idx,
+                    prev_out.lock().hash(),
+                    pkcs8_bytes,
+                    sig_hash_type,
+                ));
+            } else {
+                stack.push(Vec::new());
+            }
         }
+
+        self.witnesses[idx] = TXWitness::new(stack);
     }
 
-    pub fn trimmed_copy(&self) -> Transaction {
-        let mut inputs = Vec::new();
-        let mut outputs = Vec::new();
+    fn spent_output<'a>(tx_in: &TXInput, prev_tx: &'a Transaction) -> &'a TXOutput {
+        if prev_tx.id().is_empty() || prev_tx.id() != tx_in.tx_id() {
+            panic!("error, previous transaction is not correct");
+        }
 
-        for tx_in in self.v_in() {
-            inputs.push(TXInput::new(
-                tx_in.tx_id(),
-                tx_in.v_out(),
-                Vec::new(),
-                Vec::new(),
-            ));
+        let prev_out = &prev_tx.v_out()[tx_in.v_out() as usize];
+
+        if prev_out.is_data_carrier() {
+            panic!("error, data carrier outputs can't be spent");
+        }
+
+        prev_out
+    }
+
+    fn sign_digest(
+        &self,
+        idx: usize,
+        lock_hash: &[u8],
+        pkcs8_bytes: &[u8],
+        sig_hash_type: u8,
+    ) -> Vec<u8> {
+        let sig_hash = match self.signature_hash(idx, lock_hash, sig_hash_type) {
+            Some(sig_hash) => sig_hash,
+            None => panic!(
+                "error, sighash type {:#04x} can't be used for input {}",
+                sig_hash_type, idx
+            ),
+        };
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8_bytes))
+            .expect("error converting bytes to key pair");
+        let mut sig = key_pair.sign(sig_hash.as_bytes()).as_ref().to_vec();
+        sig.push(sig_hash_type);
+        sig
+    }
+
+    fn verify_signature(&self, idx: usize, lock_hash: &[u8], pub_key: &[u8], sig: &[u8]) -> bool {
+        let (sig, sig_hash_type) = match sig.split_last() {
+            Some((sig_hash_type, sig)) => (sig, *sig_hash_type),
+            None => return false,
+        };
+
+        let sig_hash = match self.signature_hash(idx, lock_hash, sig_hash_type) {
+            Some(sig_hash) => sig_hash,
+            None => return false,
+        };
+
+        signature::verify(
+            &signature::ED25519,
+            untrusted::Input::from(pub_key),
+            untrusted::Input::from(sig_hash.as_bytes()),
+            untrusted::Input::from(sig),
+        )
+        .is_ok()
+    }
+
+    fn verify_policy_input(&self, idx: usize, lock_hash: &[u8]) -> bool {
+        let (encoded, sigs) = match self.witnesses[idx].stack().split_first() {
+            Some(stack) => stack,
+            None => return false,
+        };
+
+        if Wallet::hash_pub_key(encoded) != lock_hash {
+            return false;
         }
 
-        for tx_out in self.v_out() {
-            outputs.push(tx_out.clone());
+        let policy = match Policy::decode(encoded) {
+            Some(policy) => policy,
+            None => return false,
+        };
+        let keys = policy.keys();
+
+        if keys.len() != sigs.len() {
+            return false;
         }
 
-        Transaction {
-            id: self.id.clone(),
-            v_in: inputs,
-            v_out: outputs,
+        let mut signed = Vec::new();
+
+        for (key, sig) in keys.iter().zip(sigs) {
+            if sig.is_empty() {
+                signed.push(false);
+            } else if self.verify_signature(idx, lock_hash, key, sig) {
+                signed.push(true);
+            } else {
+                return false;
+            }
         }
+
+        policy.is_satisfied(&signed, self.lock_time)
+    }
+
+    // Builds the digest an input signs. The base type picks which outputs are
+    // committed, ANYONECANPAY drops every input except the signed one.
+    pub fn signature_hash(
+        &self,
+        idx: usize,
+        lock_hash: &[u8],
+        sig_hash_type: u8,
+    ) -> Option<String> {
+        let mut inputs = self.v_in.clone();
+        let mut outputs = self.v_out.clone();
+
+        match sig_hash_type & !SIGHASH_ANYONECANPAY {
+            SIGHASH_ALL => (),
+            SIGHASH_NONE => outputs.clear(),
+            SIGHASH_SINGLE => {
+                if idx >= outputs.len() {
+                    return None;
+                }
+
+                outputs.truncate(idx + 1);
+
+                for out in outputs[..idx].iter_mut() {
+                    *out = TXOutput::blank();
+                }
+            }
+            _ => return None,
+        }
+
+        let mut input_idx = idx as u32;
+
+        if sig_hash_type & SIGHASH_ANYONECANPAY != 0 {
+            inputs = vec![inputs[idx].clone()];
+            input_idx = 0;
+        }
+
+        let mut data = bincode::serialize(&(inputs, outputs, self.lock_time, lock_hash, input_idx))
+            .expect("error serializing transaction");
+        data.push(sig_hash_type);
+        let mut hasher = Sha256::new();
+        hasher.input(&data);
+        Some(hasher.result_str())
     }
 
     pub fn verify(&self, prev_txs: &HashMap<String, Transaction>) -> bool {
+        let output_total = match Amount::checked_sum(self.v_out.iter().map(|out| out.value())) {
+            Some(total) => total,
+            None => return false,
+        };
+
+        if self.id != self.hash() || self.witnesses.len() != self.v_in.len() {
+            return false;
+        }
+
+        // Data carriers hold at most MAX_DATA_CARRIER_SIZE bytes and no value
+        if self.v_out.iter().any(|out| {
+            out.is_data_carrier()
+                && (out.data().len() > MAX_DATA_CARRIER_SIZE || out.value() != Amount::ZERO)
+        }) {
+            return false;
+        }
+
         if self.is_coinbase() {
             return true;
         }
@@ -155,24 +501,40 @@ impl Transaction {
             if prev_txs[tx_in.tx_id()].id().is_empty() {
                 panic!("error, previous transaction is not correct");
             }
+
+            if prev_txs[tx_in.tx_id()].v_out()[tx_in.v_out() as usize].is_data_carrier() {
+                return false;
+            }
         }
 
-        let mut tx_copy = self.trimmed_copy();
+        let input_total = Amount::checked_sum(
+            self.v_in
+                .iter()
+                .map(|tx_in| prev_txs[tx_in.tx_id()].v_out()[tx_in.v_out() as usize].value()),
+        );
+
+        match input_total {
+            Some(input_total) if input_total >= output_total => (),
+            _ => return false,
+        }
 
         for (i, tx_in) in self.v_in().iter().enumerate() {
-            let prev_tx = &prev_txs[tx_in.tx_id()];
-            let tx_copy_in = &mut tx_copy.mut_v_in()[i];
-            tx_copy_in.set_pub_key(prev_tx.v_out()[tx_in.v_out() as usize].pub_key_hash());
-            tx_copy.set_id();
-
-            match signature::verify(
-                &signature::ED25519,
-                untrusted::Input::from(tx_in.pub_key()),
-                untrusted::Input::from(tx_copy.id().as_bytes()),
-                untrusted::Input::from(tx_in.signature()),
-            ) {
-                Err(_) => return false,
-                _ => continue,
+            let prev_out = &prev_txs[tx_in.tx_id()].v_out()[tx_in.v_out() as usize];
+
+            let valid = match prev_out.lock() {
+                Lock::PubKeyHash(pub_key_hash) => match self.witnesses[i].stack() {
+                    [sig, pub_key] => {
+                        Wallet::hash_pub_key(pub_key) == *pub_key_hash
+                            && self.verify_signature(i, pub_key_hash, pub_key, sig)
+                    }
+                    _ => false,
+                },
+                Lock::PolicyHash(policy_hash) => self.verify_policy_input(i, policy_hash),
+                Lock::Data(_) => false,
+            };
+
+            if !valid {
+                return false;
             }
         }
 
@@ -180,10 +542,24 @@ impl Transaction {
     }
 
     fn set_id(&mut self) {
+        self.id = self.hash();
+    }
+
+    // The id covers inputs and outputs only, so rewriting witnesses can't change it
+    pub fn hash(&self) -> String {
         let mut hasher = Sha256::new();
-        let data = bincode::serialize(&self).expect("error serializing transaction");
+        let data = bincode::serialize(&(&self.v_in, &self.v_out, self.lock_time))
+            .expect("error serializing transaction");
         hasher.input(&data);
-        self.id = hasher.result_str()
+        hasher.result_str()
+    }
+
+    pub fn witness_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        let data = bincode::serialize(&(&self.v_in, &self.v_out, self.lock_time, &self.witnesses))
+            .expect("error serializing transaction");
+        hasher.input(&data);
+        hasher.result_str()
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -202,10 +578,6 @@ impl Transaction {
         &self.v_in
     }
 
-    pub fn mut_v_in(&mut self) -> &mut [TXInput] {
-        &mut self.v_in
-    }
-
     pub fn v_out(&self) -> &[TXOutput] {
         &self.v_out
     }
@@ -214,19 +586,38 @@ impl Transaction {
 impl ToString for Transaction {
     fn to_string(&self) -> String {
         let mut lines = String::from(format!("--- Transaction {}:\n", self.id));
+        lines.push_str(&format!("     WTXID: {}\n", self.witness_hash())[..]);
+
+        if self.lock_time > 0 {
+            lines.push_str(&format!("     Lock time: {}\n", self.lock_time)[..]);
+        }
 
         for (i, input) in self.v_in().iter().enumerate() {
             lines.push_str(&format!("     Input {}:\n", i)[..]);
             lines.push_str(&format!("       TXID:      {}\n", input.tx_id())[..]);
             lines.push_str(&format!("       Out:       {}\n", input.v_out())[..]);
-            lines.push_str(&format!("       Signature: {:?}\n", input.signature())[..]);
-            lines.push_str(&format!("       PubKey:    {:?}\n\n", input.pub_key())[..]);
+            lines.push_str(&format!("       Data:      {:?}\n", input.data())[..]);
+
+            for item in self.witnesses[i].stack() {
+                lines.push_str(&format!("       Witness:   {:?}\n", item)[..]);
+            }
+
+            lines.push('\n');
         }
 
         for (i, output) in self.v_out().iter().enumerate() {
             lines.push_str(&format!("     Output {}:\n", i)[..]);
             lines.push_str(&format!("       Value:  {}\n", output.value())[..]);
-            lines.push_str(&format!("       Script: {:?}\n\n", output.pub_key_hash())[..]);
+
+            match output.lock() {
+                Lock::PubKeyHash(hash) => {
+                    lines.push_str(&format!("       Script: {:?}\n\n", hash)[..])
+                }
+                Lock::PolicyHash(hash) => {
+                    lines.push_str(&format!("       Policy: {:?}\n\n", hash)[..])
+                }
+                Lock::Data(data) => lines.push_str(&format!("       Data:   {:?}\n\n", data)[..]),
+            }
         }
 
         lines
@@ -237,26 +628,18 @@ impl ToString for Transaction {
 pub struct TXInput {
     tx_id: String,
     v_out: i32,
-    signature: Vec<u8>,
-    public_key: Vec<u8>,
+    data: Vec<u8>,
 }
 
 impl TXInput {
-    pub fn new(tx_id: &str, v_out: i32, signature: Vec<u8>, public_key: Vec<u8>) -> TXInput {
+    pub fn new(tx_id: &str, v_out: i32, data: Vec<u8>) -> TXInput {
         TXInput {
             tx_id: tx_id.to_string(),
             v_out,
-            signature,
-            public_key,
+            data,
         }
     }
 
-    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
-        let locking_hash = Wallet::hash_pub_key(&self.public_key);
-        locking_hash.len() == pub_key_hash.len()
-            && locking_hash.iter().zip(pub_key_hash).all(|(a, b)| a == b)
-    }
-
     pub fn tx_id(&self) -> &str {
         &self.tx_id[..]
     }
@@ -265,81 +648,370 @@ impl TXInput {
         self.v_out
     }
 
-    pub fn signature(&self) -> &[u8] {
-        &self.signature[..]
+    pub fn data(&self) -> &[u8] {
+        &self.data[..]
     }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TXWitness {
+    stack: Vec<Vec<u8>>,
+}
 
-    pub fn set_signature(&mut self, signature: &[u8]) {
-        self.signature = signature.to_vec()
+impl TXWitness {
+    pub fn new(stack: Vec<Vec<u8>>) -> TXWitness {
+        TXWitness { stack }
     }
 
-    pub fn pub_key(&self) -> &[u8] {
-        &self.public_key[..]
+    pub fn stack(&self) -> &[Vec<u8>] {
+        &self.stack[..]
     }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Lock {
+    PubKeyHash(Vec<u8>),
+    PolicyHash(Vec<u8>),
+    Data(Vec<u8>),
+}
 
-    pub fn set_pub_key(&mut self, bytes: &[u8]) {
-        self.public_key = bytes.to_vec();
+impl Lock {
+    pub fn hash(&self) -> &[u8] {
+        match self {
+            Lock::PubKeyHash(hash) | Lock::PolicyHash(hash) => &hash[..],
+            Lock::Data(_) => &[],
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TXOutput {
-    value: i32,
-    pub_key_hash: Vec<u8>,
+    value: Amount,
+    lock: Lock,
 }
 
 impl TXOutput {
-    pub fn new(amount: i32, address: &str) -> TXOutput {
-        let mut txo = TXOutput {
+    pub fn new(amount: Amount, address: &str) -> TXOutput {
+        TXOutput {
             value: amount,
-            pub_key_hash: Vec::new(),
-        };
-        txo.lock(address);
-        txo
+            lock: TXOutput::lock_for(address),
+        }
+    }
+
+    pub fn from_lock(value: Amount, lock: Lock) -> TXOutput {
+        TXOutput { value, lock }
+    }
+
+    fn blank() -> TXOutput {
+        TXOutput {
+            value: Amount::ZERO,
+            lock: Lock::Data(Vec::new()),
+        }
+    }
+
+    // Data carrier outputs have no locking key, so no input can ever satisfy them
+    pub fn new_data_carrier(data: &[u8]) -> TXOutput {
+        if data.len() > MAX_DATA_CARRIER_SIZE {
+            panic!(
+                "data carrier output can hold at most {} bytes, got {}",
+                MAX_DATA_CARRIER_SIZE,
+                data.len()
+            );
+        }
+
+        TXOutput {
+            value: Amount::ZERO,
+            lock: Lock::Data(data.to_vec()),
+        }
     }
 
-    fn lock(&mut self, address: &str) {
-        let pub_key_hash = bs58::decode(address)
+    fn lock_for(address: &str) -> Lock {
+        let payload = bs58::decode(address)
             .into_vec()
             .expect("error decoding address using base 58");
-        let size = pub_key_hash.len() - wallet::ADDRESS_CHECKSUM_LEN;
-        let pub_key_hash = pub_key_hash[1..size].to_vec();
-        self.pub_key_hash = pub_key_hash;
-    }
+        let size = payload.len() - wallet::ADDRESS_CHECKSUM_LEN;
+        let hash = payload[1..size].to_vec();
 
-    pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash.len() == pub_key_hash.len()
-            && self
-                .pub_key_hash
-                .iter()
-                .zip(pub_key_hash)
-                .all(|(a, b)| a == b)
+        match payload[0] {
+            wallet::POLICY_VERSION => Lock::PolicyHash(hash),
+            _ => Lock::PubKeyHash(hash),
+        }
     }
 
-    pub fn value(&self) -> i32 {
+    pub fn value(&self) -> Amount {
         self.value
     }
 
-    pub fn pub_key_hash(&self) -> &[u8] {
-        &self.pub_key_hash
+    pub fn lock(&self) -> &Lock {
+        &self.lock
     }
-}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub fn is_data_carrier(&self) -> bool {
+        matches!(self.lock, Lock::Data(_))
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match &self.lock {
+            Lock::Data(data) => &data[..],
+            _ => &[],
+        }
+    }
 }
 
-impl TXOutputs {
-    pub fn new(outputs: Vec<TXOutput>) -> TXOutputs {
-        TXOutputs { outputs }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Change {
+        OwnInput,
+        OtherInput,
+        AddInput,
+        OwnOutput,
+        OtherOutput,
+        AddOutput,
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(&self).expect("error serializing TXOutputs")
+    const CHANGES: [Change; 6] = [
+        Change::OwnInput,
+        Change::OtherInput,
+        Change::AddInput,
+        Change::OwnOutput,
+        Change::OtherOutput,
+        Change::AddOutput,
+    ];
+
+    fn coins(coins: u64) -> Amount {
+        Amount::from_coins(coins).unwrap()
+    }
+
+    // A previous transaction paying the wallet four times and a transaction
+    // spending the first two of those outputs into two outputs
+    fn spending_tx(wallet: &Wallet) -> (Transaction, Transaction) {
+        let address = wallet.get_address();
+        let mut prev_tx = Transaction {
+            id: String::new(),
+            v_in: vec![TXInput::new("", -1, Vec::new())],
+            v_out: (1..=4)
+                .map(|value| TXOutput::new(coins(value), &address))
+                .collect(),
+            witnesses: vec![TXWitness::default()],
+            lock_time: 0,
+        };
+        prev_tx.set_id();
+
+        let mut tx = Transaction {
+            id: String::new(),
+            v_in: vec![
+                TXInput::new(prev_tx.id(), 0, Vec::new()),
+                TXInput::new(prev_tx.id(), 1, Vec::new()),
+            ],
+            v_out: vec![
+                TXOutput::new(coins(2), &address),
+                TXOutput::new(coins(1), &address),
+            ],
+            witnesses: vec![TXWitness::default(); 2],
+            lock_time: 0,
+        };
+        tx.set_id();
+        (prev_tx, tx)
+    }
+
+    fn apply(tx: &mut Transaction, idx: usize, change: Change, address: &str) {
+        let other = 1 - idx;
+        let prev_id = tx.v_in[idx].tx_id.clone();
+
+        match change {
+            Change::OwnInput => tx.v_in[idx] = TXInput::new(&prev_id, 2, Vec::new()),
+            Change::OtherInput => tx.v_in[other] = TXInput::new(&prev_id, 2, Vec::new()),
+            Change::AddInput => {
+                tx.v_in.push(TXInput::new(&prev_id, 3, Vec::new()));
+                tx.witnesses.push(TXWitness::default());
+            }
+            Change::OwnOutput => tx.v_out[idx] = TXOutput::new(coins(3), address),
+            Change::OtherOutput => tx.v_out[other] = TXOutput::new(coins(3), address),
+            Change::AddOutput => tx.v_out.push(TXOutput::new(coins(1), address)),
+        }
+    }
+
+    // Signs input idx with the sighash type, applies the change and tells
+    // whether the signature still verifies
+    fn survives(sig_hash_type: u8, idx: usize, change: Change) -> bool {
+        let wallet = Wallet::new();
+        let pub_key_hash = Wallet::hash_pub_key(wallet.public_key());
+        let (prev_tx, mut tx) = spending_tx(&wallet);
+
+        tx.sign_input(idx, wallet.pkcs8_bytes(), &prev_tx, sig_hash_type);
+        assert!(tx.verify_signature(
+            idx,
+            &pub_key_hash,
+            wallet.public_key(),
+            &tx.witnesses[idx].stack()[0]
+        ));
+
+        let sig = tx.witnesses[idx].stack()[0].clone();
+        apply(&mut tx, idx, change, &wallet.get_address());
+        tx.verify_signature(idx, &pub_key_hash, wallet.public_key(), &sig)
+    }
+
+    fn check(sig_hash_type: u8, idx: usize, survived: &[Change]) {
+        for change in CHANGES.iter() {
+            assert_eq!(
+                survives(sig_hash_type, idx, *change),
+                survived.contains(change),
+                "sighash type {:#04x} on input {}, change {:?}",
+                sig_hash_type,
+                idx,
+                change
+            );
+        }
+    }
+
+    #[test]
+    fn sighash_all_commits_to_everything() {
+        check(SIGHASH_ALL, 0, &[]);
+        check(SIGHASH_ALL, 1, &[]);
+    }
+
+    #[test]
+    fn sighash_none_leaves_outputs_open() {
+        let outputs = [Change::OwnOutput, Change::OtherOutput, Change::AddOutput];
+        check(SIGHASH_NONE, 0, &outputs);
+        check(SIGHASH_NONE, 1, &outputs);
+    }
+
+    #[test]
+    fn sighash_single_commits_to_the_matching_output() {
+        // Outputs after the matching one aren't committed, ones before it only by position
+        check(SIGHASH_SINGLE, 0, &[Change::OtherOutput, Change::AddOutput]);
+        check(SIGHASH_SINGLE, 1, &[Change::OtherOutput, Change::AddOutput]);
+    }
+
+    #[test]
+    fn sighash_all_anyonecanpay_leaves_inputs_open() {
+        check(
+            SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+            0,
+            &[Change::OtherInput, Change::AddInput],
+        );
+    }
+
+    #[test]
+    fn sighash_none_anyonecanpay_commits_to_own_input_only() {
+        check(
+            SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+            0,
+            &[
+                Change::OtherInput,
+                Change::AddInput,
+                Change::OwnOutput,
+                Change::OtherOutput,
+                Change::AddOutput,
+            ],
+        );
+    }
+
+    #[test]
+    fn sighash_single_anyonecanpay_commits_to_own_input_and_output() {
+        check(
+            SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+            1,
+            &[
+                Change::OtherInput,
+                Change::AddInput,
+                Change::OtherOutput,
+                Change::AddOutput,
+            ],
+        );
+    }
+
+    // Digests of input 1 of a transaction with fixed inputs and outputs,
+    // worked out by hand from the bincode layout of the signed data
+    #[test]
+    fn sighash_digests_match_vectors() {
+        let prev_id = "11".repeat(32);
+        let tx = Transaction {
+            id: String::new(),
+            v_in: vec![
+                TXInput::new(&prev_id, 0, Vec::new()),
+                TXInput::new(&prev_id, 1, Vec::new()),
+            ],
+            v_out: vec![
+                TXOutput {
+                    value: coins(2),
+                    lock: Lock::PubKeyHash(vec![0x22; 20]),
+                },
+                TXOutput {
+                    value: coins(1),
+                    lock: Lock::PubKeyHash(vec![0x33; 20]),
+                },
+            ],
+            witnesses: vec![TXWitness::default(); 2],
+            lock_time: 0,
+        };
+        let vectors = [
+            (
+                SIGHASH_ALL,
+                "85e749ceaae5f819490c96faa7369fd3f76681bde14a14e91517d209292595d6",
+            ),
+            (
+                SIGHASH_NONE,
+                "55ab6d86535aeb63f75d687c5649db51fe43057e1b07785e665eff99694bb99c",
+            ),
+            (
+                SIGHASH_SINGLE,
+                "ad58ead29d84610a310d609020a3b802b3c3d2b1cc45c5cce23f8ecccbde2adc",
+            ),
+            (
+                SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+                "966a7a80fc071fd5b01d1f2c6850d430c05bad8d16723ad4df04120626bf6132",
+            ),
+            (
+                SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+                "e3f5882a89a4e1b5d0f69ab358fe39d97e45deb1073029aba2c61f3460d2590e",
+            ),
+            (
+                SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+                "aae9bd375861129cf5fb87eb5c78649da7e1f64bc7fc83b5e0e4bb99a7c72267",
+            ),
+        ];
+
+        for (sig_hash_type, digest) in vectors.iter() {
+            assert_eq!(
+                tx.signature_hash(1, &[0x44; 20], *sig_hash_type).as_deref(),
+                Some(*digest),
+                "sighash type {:#04x}",
+                sig_hash_type
+            );
+        }
+    }
+
+    #[test]
+    fn sighash_single_without_matching_output_has_no_digest() {
+        let wallet = Wallet::new();
+        let pub_key_hash = Wallet::hash_pub_key(wallet.public_key());
+        let (prev_tx, mut tx) = spending_tx(&wallet);
+        tx.v_out.truncate(1);
+
+        for sig_hash_type in [SIGHASH_SINGLE, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY].iter() {
+            assert!(tx
+                .signature_hash(1, &pub_key_hash, *sig_hash_type)
+                .is_none());
+        }
+
+        // A signature relabeled as SINGLE can't stand in for one over an output that doesn't exist
+        tx.sign_input(1, wallet.pkcs8_bytes(), &prev_tx, SIGHASH_ALL);
+        let mut sig = tx.witnesses[1].stack()[0].clone();
+        *sig.last_mut().unwrap() = SIGHASH_SINGLE;
+        assert!(!tx.verify_signature(1, &pub_key_hash, wallet.public_key(), &sig));
     }
 
-    pub fn deserialize(bytes: Vec<u8>) -> TXOutputs {
-        bincode::deserialize(&bytes[..]).expect("error decerializing TXOutputs")
+    #[test]
+    #[should_panic(expected = "can't be used for input 1")]
+    fn sighash_single_without_matching_output_refuses_to_sign() {
+        let wallet = Wallet::new();
+        let (prev_tx, mut tx) = spending_tx(&wallet);
+        tx.v_out.truncate(1);
+        tx.sign_input(1, wallet.pkcs8_bytes(), &prev_tx, SIGHASH_SINGLE);
     }
 }
@@ -0,0 +1,194 @@
+use crate::storage::{Storage, WriteBatch};
+
+use std::collections::BTreeMap;
+
+pub const DEFAULT_CACHE_SIZE: usize = 32 * 1024 * 1024;
+const ENTRY_OVERHEAD: usize = 64;
+
+struct CacheEntry {
+    value: Option<Vec<u8>>,
+    dirty: bool,
+    // Not on disk, so removing it needs no write at all
+    fresh: bool,
+}
+
+// Write-back cache over a storage, holding values as the raw bytes the
+// storage keeps. Reads are cached, writes stay in memory until flush, which
+// happens when the cache outgrows its budget at a call to maybe_flush, or
+// when the cache is dropped.
+pub struct UTXOCache {
+    storage: Box<dyn Storage>,
+    // Ordered, so a prefix scan only visits the keys under the prefix
+    entries: BTreeMap<String, CacheEntry>,
+    usage: usize,
+    budget: usize,
+}
+
+impl UTXOCache {
+    pub fn new(storage: Box<dyn Storage>, budget: usize) -> UTXOCache {
+        UTXOCache {
+            storage,
+            entries: BTreeMap::new(),
+            usage: 0,
+            budget,
+        }
+    }
+
+    fn entry_size(key: &str, value: &Option<Vec<u8>>) -> usize {
+        ENTRY_OVERHEAD + key.len() + value.as_ref().map_or(0, |value| value.len())
+    }
+
+    fn put(&mut self, key: String, entry: CacheEntry) {
+        self.usage += UTXOCache::entry_size(&key, &entry.value);
+
+        if let Some(old) = self.entries.insert(key.clone(), entry) {
+            self.usage -= UTXOCache::entry_size(&key, &old.value);
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        if let Some(entry) = self.entries.get(key) {
+            return Ok(entry.value.clone());
+        }
+
+        let value = self
+            .storage
+            .get(key.as_bytes())
+            .map_err(|err| err.to_string())?;
+        self.put(
+            key.to_string(),
+            CacheEntry {
+                fresh: value.is_none(),
+                value: value.clone(),
+                dirty: false,
+            },
+        );
+        Ok(value)
+    }
+
+    pub fn insert(&mut self, key: String, value: Vec<u8>) -> Result<(), String> {
+        let fresh = match self.entries.get(&key) {
+            Some(entry) => entry.fresh,
+            None => self
+                .storage
+                .get(key.as_bytes())
+                .map_err(|err| err.to_string())?
+                .is_none(),
+        };
+        self.put(
+            key,
+            CacheEntry {
+                value: Some(value),
+                dirty: true,
+                fresh,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<(), String> {
+        // Created and removed inside the cache window, the store never sees it
+        if self.entries.get(key).is_some_and(|entry| entry.fresh) {
+            if let Some(old) = self.entries.remove(key) {
+                self.usage -= UTXOCache::entry_size(key, &old.value);
+            }
+
+            return Ok(());
+        }
+
+        self.put(
+            key.to_string(),
+            CacheEntry {
+                value: None,
+                dirty: true,
+                fresh: false,
+            },
+        );
+        Ok(())
+    }
+
+    // Pairs with keys starting with prefix in key order, pending writes included
+    pub fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+        let mut pairs = BTreeMap::new();
+
+        for (key, value) in self
+            .storage
+            .scan_prefix(prefix.as_bytes())
+            .map_err(|err| err.to_string())?
+        {
+            let key = String::from_utf8(key).map_err(|_| "error, store key is not UTF-8")?;
+            pairs.insert(key, value);
+        }
+
+        for (key, entry) in self
+            .entries
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+        {
+            match &entry.value {
+                Some(value) => pairs.insert(key.clone(), value.clone()),
+                None => pairs.remove(key),
+            };
+        }
+
+        Ok(pairs.into_iter().collect())
+    }
+
+    pub fn maybe_flush(&mut self) {
+        if self.usage > self.budget {
+            self.flush();
+        }
+    }
+
+    // Writes every dirty entry in a single batch, so the store never holds half a flush
+    pub fn flush(&mut self) {
+        let mut batch = WriteBatch::new();
+
+        for (key, entry) in std::mem::take(&mut self.entries) {
+            if !entry.dirty {
+                continue;
+            }
+
+            match entry.value {
+                Some(value) => batch.put(key.as_bytes(), &value),
+                None => batch.delete(key.as_bytes()),
+            }
+        }
+
+        if !batch.is_empty() {
+            match self.storage.write_batch(batch) {
+                Ok(_) => (),
+                Err(err) => panic!("error while flushing UTXO cache to store: {}", err),
+            };
+        }
+
+        self.usage = 0;
+    }
+
+    // Drops pending writes and everything in the store, in a single batch
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.usage = 0;
+
+        let mut batch = WriteBatch::new();
+
+        for (key, _) in self
+            .storage
+            .scan_prefix(&[])
+            .expect("error getting keys from store")
+        {
+            batch.delete(&key);
+        }
+
+        match self.storage.write_batch(batch) {
+            Ok(_) => (),
+            Err(err) => panic!("error while clearing store: {}", err),
+        };
+    }
+}
+
+impl Drop for UTXOCache {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
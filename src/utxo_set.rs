@@ -1,175 +1,988 @@
-use crate::block::Block;
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
 use crate::blockchain::Blockchain;
-use crate::transaction::{TXOutput, TXOutputs};
+use crate::coin_selection::{Coin, CoinSelector, Selection};
+use crate::compact;
+use crate::datadir::DataDir;
+use crate::muhash::MuHash;
+use crate::storage::{Storage, Store};
+use crate::transaction::{TXOutput, Transaction};
+use crate::utxo_cache::{self, UTXOCache};
 
-use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
-use typedb::{value, KV};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use typedb::value;
 
+// Record layout of stores written before values were kept as raw bytes,
+// only read to migrate them
 value!(
-    enum StoreValue {
+    enum LegacyValue {
         String(String),
-        TXOutputs(Vec<u8>),
+        UTXOEntry(Vec<u8>),
+        BlockUndo(Vec<u8>),
+        Snapshot(Vec<u8>),
+        MuHash(Vec<u8>),
     }
 );
 
+const ENTRY_PREFIX: &str = "utxo_";
+const BEST_BLOCK_KEY: &str = "best_block";
+const UNDO_PREFIX: &str = "undo_";
+const ADDRESS_PREFIX: &str = "addr_";
+const PENDING_SNAPSHOT_KEY: &str = "pending_snapshot";
+const MUHASH_KEY: &str = "muhash";
+const FORMAT_KEY: &str = "format";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UTXOEntry {
+    output: TXOutput,
+    height: i32,
+    is_coinbase: bool,
+}
+
+impl UTXOEntry {
+    pub fn new(output: TXOutput, height: i32, is_coinbase: bool) -> UTXOEntry {
+        UTXOEntry {
+            output,
+            height,
+            is_coinbase,
+        }
+    }
+
+    pub fn output(&self) -> &TXOutput {
+        &self.output
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.is_coinbase
+    }
+
+    // Compact record: version, height and coinbase flag in one varint, then the output
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![compact::VERSION];
+        self.write(&mut buf);
+        buf
+    }
+
+    pub fn deserialize(bytes: Vec<u8>) -> UTXOEntry {
+        let mut data = &bytes[..];
+        compact::check_version(&mut data, "UTXOEntry");
+        UTXOEntry::read(&mut data)
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        let code = (self.height as u64) << 1 | self.is_coinbase as u64;
+        compact::write_varint(buf, code);
+        compact::write_output(buf, &self.output);
+    }
+
+    fn read(data: &mut &[u8]) -> UTXOEntry {
+        let code = compact::read_varint(data);
+        UTXOEntry {
+            height: (code >> 1) as i32,
+            is_coinbase: code & 1 == 1,
+            output: compact::read_output(data),
+        }
+    }
+}
+
+// Outputs a block spent, in the order it spent them, so the block can be disconnected
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockUndo {
+    spent: Vec<(String, UTXOEntry)>,
+}
+
+impl BlockUndo {
+    // Outpoints first so runs from one transaction share the TXID, then the entries
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![compact::VERSION];
+        let outpoints: Vec<String> = self.spent.iter().map(|(key, _)| key.clone()).collect();
+        compact::write_outpoints(&mut buf, &outpoints);
+
+        for (_, entry) in &self.spent {
+            entry.write(&mut buf);
+        }
+
+        buf
+    }
+
+    fn deserialize(bytes: Vec<u8>) -> BlockUndo {
+        let mut data = &bytes[..];
+        compact::check_version(&mut data, "BlockUndo");
+        let outpoints = compact::read_outpoints(&mut data);
+        let spent = outpoints
+            .into_iter()
+            .map(|key| (key, UTXOEntry::read(&mut data)))
+            .collect();
+        BlockUndo { spent }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UTXOSnapshot {
+    block_hash: String,
+    height: i32,
+    content_hash: String,
+    entries: Vec<(String, UTXOEntry)>,
+    // From genesis up to the snapshot block, so a node can load it before
+    // it has downloaded the blocks
+    headers: Vec<BlockHeader>,
+}
+
+impl UTXOSnapshot {
+    pub fn block_hash(&self) -> &str {
+        &self.block_hash[..]
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn content_hash(&self) -> &str {
+        &self.content_hash[..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(&self).expect("error serializing UTXOSnapshot")
+    }
+
+    pub fn deserialize(bytes: Vec<u8>) -> UTXOSnapshot {
+        bincode::deserialize(&bytes[..]).expect("error decerializing UTXOSnapshot")
+    }
+}
+
+// Hash over every entry in outpoint order, so equal sets hash equally however they were built
+pub fn content_hash(utxo: &BTreeMap<String, UTXOEntry>) -> String {
+    let mut hasher = Sha256::new();
+
+    for (key, entry) in utxo {
+        hasher.input(&entry_data(key, entry));
+    }
+
+    hasher.result_str()
+}
+
+// What the set hashes and commitments are computed over for each entry
+fn entry_data(key: &str, entry: &UTXOEntry) -> Vec<u8> {
+    bincode::serialize(&(key, entry)).expect("error serializing UTXOEntry")
+}
+
+// Applies the outputs transactions create and spend to a rolling commitment.
+// Outputs created earlier in the same list are found without calling lookup.
+fn commit_transactions<F: FnMut(&str) -> Option<UTXOEntry>>(
+    muhash: &mut MuHash,
+    transactions: &[Transaction],
+    height: i32,
+    mut lookup: F,
+) -> Result<(), String> {
+    let mut created = HashMap::new();
+
+    for tx in transactions {
+        if !tx.is_coinbase() {
+            for tx_in in tx.v_in() {
+                let key = outpoint(tx_in.tx_id(), tx_in.v_out());
+                let entry = match created.remove(&key) {
+                    Some(entry) => entry,
+                    None => match lookup(&key) {
+                        Some(entry) => entry,
+                        None => return Err(format!("output {} is not in the UTXO set", key)),
+                    },
+                };
+                muhash.remove(&entry_data(&key, &entry));
+            }
+        }
+
+        for (idx, out) in tx.v_out().iter().enumerate() {
+            if !out.is_data_carrier() {
+                let key = outpoint(tx.id(), idx as i32);
+                let entry = UTXOEntry::new(out.clone(), height, tx.is_coinbase());
+                muhash.insert(&entry_data(&key, &entry));
+                created.insert(key, entry);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn genesis_commitment(coinbase: &Transaction) -> String {
+    let mut muhash = MuHash::new();
+    commit_transactions(&mut muhash, std::slice::from_ref(coinbase), 0, |_| None)
+        .expect("error, a coinbase spends no outputs");
+    muhash.finalize()
+}
+
+// Same as UTXOSet::update, on an in-memory set
+pub fn apply_block(utxo: &mut BTreeMap<String, UTXOEntry>, block: &Block) {
+    for tx in block.transactions() {
+        if !tx.is_coinbase() {
+            for tx_in in tx.v_in() {
+                let key = outpoint(tx_in.tx_id(), tx_in.v_out());
+
+                if utxo.remove(&key).is_none() {
+                    panic!("error, output {} is not in the UTXO set", key);
+                }
+            }
+        }
+
+        for (idx, out) in tx.v_out().iter().enumerate() {
+            if !out.is_data_carrier() {
+                utxo.insert(
+                    outpoint(tx.id(), idx as i32),
+                    UTXOEntry::new(out.clone(), block.height(), tx.is_coinbase()),
+                );
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UTXOSetInfo {
+    pub best_block: Option<String>,
+    pub outputs: usize,
+    pub total: Amount,
+    pub serialized_size: usize,
+    pub hash: String,
+}
+
+// Difference between the set rebuilt from the chain and the one in store
+#[derive(Debug)]
+pub struct UTXODiff {
+    pub missing: Vec<(String, UTXOEntry)>,
+    pub extra: Vec<(String, UTXOEntry)>,
+    // Outpoint, entry rebuilt from the chain and entry found in store
+    pub mismatched: Vec<(String, UTXOEntry, UTXOEntry)>,
+}
+
+impl UTXODiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+// Rebuilding the set replays every block, which a pruned node no longer has
+fn require_full_chain(bc: &Blockchain) {
+    if let Some(height) = bc.pruned_height() {
+        panic!(
+            "ERROR: The UTXO set can't be rebuilt, blocks up to height {} are pruned",
+            height
+        );
+    }
+}
+
+pub fn outpoint(tx_id: &str, v_out: i32) -> String {
+    format!("{}:{}", tx_id, v_out)
+}
+
+// Index keys of an address share the prefix addr_HASH_, the outpoint follows it
+fn address_prefix(pub_key_hash: &[u8]) -> String {
+    let hex: String = pub_key_hash.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}_", ADDRESS_PREFIX, hex)
+}
+
+fn address_outpoint_key(pub_key_hash: &[u8], outpoint: &str) -> String {
+    format!("{}{}", address_prefix(pub_key_hash), outpoint)
+}
+
+fn entry_key(outpoint: &str) -> String {
+    format!("{}{}", ENTRY_PREFIX, outpoint)
+}
+
+fn parse_outpoint(key: &str) -> (&str, i32) {
+    match key.rfind(':') {
+        Some(idx) => (
+            &key[..idx],
+            key[idx + 1..]
+                .parse::<i32>()
+                .expect("error parsing output index of outpoint"),
+        ),
+        None => panic!("error, malformed outpoint key {}", key),
+    }
+}
+
 pub struct UTXOSet {
-    store: KV<String, StoreValue>,
+    store: UTXOCache,
 }
 
 impl UTXOSet {
-    pub fn new(node_id: &str) -> UTXOSet {
-        let db_file = Box::leak(Box::new(format!("utxo_set_{}.db", node_id)));
-        UTXOSet {
-            store: KV::<String, StoreValue>::new(db_file).expect("error opening utxo set store"),
+    pub fn new(data_dir: &DataDir) -> UTXOSet {
+        UTXOSet::open(Store::<LegacyValue>::open("utxo_set", data_dir).into_storage())
+    }
+
+    #[cfg(test)]
+    pub fn with_storage(storage: Box<dyn Storage>) -> UTXOSet {
+        UTXOSet::open(storage)
+    }
+
+    // Cache budget is taken from UTXO_CACHE_MB in env, if set
+    fn open(storage: Box<dyn Storage>) -> UTXOSet {
+        let budget = match env::var("UTXO_CACHE_MB") {
+            Ok(mb) => mb.parse::<usize>().expect("error parsing UTXO_CACHE_MB") * 1024 * 1024,
+            Err(_) => utxo_cache::DEFAULT_CACHE_SIZE,
+        };
+        let mut utxo_set = UTXOSet {
+            store: UTXOCache::new(storage, budget),
+        };
+        utxo_set.migrate();
+        utxo_set
+    }
+
+    // Stores written before the compact encoding have no format key and keep
+    // every record bincode encoded, entries and undo data included
+    fn migrate(&mut self) {
+        let version = compact::VERSION.to_string();
+
+        match self
+            .store
+            .get(FORMAT_KEY)
+            .expect("error getting store format")
+        {
+            Some(format) if format == version.as_bytes() => return,
+            Some(format) => panic!(
+                "error, unknown UTXO store format {}",
+                String::from_utf8_lossy(&format)
+            ),
+            None => (),
+        };
+
+        let mut migrated = 0;
+
+        for (key, value) in self.store.scan_prefix("").expect("error scanning store") {
+            let value: LegacyValue =
+                bincode::deserialize(&value[..]).expect("error decerializing legacy record");
+            let (key, value) = match value {
+                LegacyValue::UTXOEntry(bytes) => {
+                    let entry: UTXOEntry =
+                        bincode::deserialize(&bytes[..]).expect("error decerializing UTXOEntry");
+                    self.remove_record(&key);
+                    (entry_key(&key), entry.serialize())
+                }
+                LegacyValue::BlockUndo(bytes) => {
+                    let spent =
+                        bincode::deserialize(&bytes[..]).expect("error decerializing BlockUndo");
+                    (key, BlockUndo { spent }.serialize())
+                }
+                LegacyValue::String(_) if key.starts_with(ADDRESS_PREFIX) => (key, Vec::new()),
+                LegacyValue::String(string) => (key, string.into_bytes()),
+                LegacyValue::Snapshot(bytes) | LegacyValue::MuHash(bytes) => (key, bytes),
+            };
+
+            match self.store.insert(key, value) {
+                Ok(_) => (),
+                Err(err) => panic!("error while migrating UTXO store: {}", err),
+            };
+            migrated += 1;
+        }
+
+        match self
+            .store
+            .insert(FORMAT_KEY.to_string(), version.into_bytes())
+        {
+            Ok(_) => (),
+            Err(err) => panic!("error while putting store format into store: {}", err),
+        };
+        self.store.flush();
+
+        if migrated > 0 {
+            println!(
+                "Migrated {} UTXO store records to the compact format",
+                migrated
+            );
+        }
+    }
+
+    fn remove_record(&mut self, key: &str) {
+        match self.store.remove(key) {
+            Ok(_) => (),
+            Err(err) => panic!("error while removing record from store: {}", err),
+        };
+    }
+
+    pub fn get(&mut self, tx_id: &str, v_out: i32) -> Option<UTXOEntry> {
+        self.get_entry(&outpoint(tx_id, v_out))
+    }
+
+    fn get_entry(&mut self, key: &str) -> Option<UTXOEntry> {
+        self.store
+            .get(&entry_key(key))
+            .expect("error getting UTXOEntry from store")
+            .map(UTXOEntry::deserialize)
+    }
+
+    pub fn contains(&mut self, tx_id: &str, v_out: i32) -> bool {
+        self.get(tx_id, v_out).is_some()
+    }
+
+    pub fn spend(&mut self, tx_id: &str, v_out: i32) -> UTXOEntry {
+        let key = outpoint(tx_id, v_out);
+        let entry = match self.get_entry(&key) {
+            Some(entry) => entry,
+            None => panic!("error, output {} is not in the UTXO set", key),
+        };
+
+        match self.store.remove(&entry_key(&key)) {
+            Ok(_) => (),
+            Err(err) => panic!("error while removing UTXOEntry data from store: {}", err),
+        };
+
+        let mut muhash = self.muhash();
+        muhash.remove(&entry_data(&key, &entry));
+        self.set_muhash(&muhash);
+
+        match self
+            .store
+            .remove(&address_outpoint_key(entry.output().lock().hash(), &key))
+        {
+            Ok(_) => (),
+            Err(err) => panic!("error while updating address index in store: {}", err),
+        };
+
+        entry
+    }
+
+    // The outpoints are read off the index keys, no value is decoded
+    fn address_outpoints(&mut self, pub_key_hash: &[u8]) -> Vec<String> {
+        let prefix = address_prefix(pub_key_hash);
+
+        self.store
+            .scan_prefix(&prefix)
+            .expect("error scanning address index in store")
+            .into_iter()
+            .map(|(key, _)| key[prefix.len()..].to_string())
+            .collect()
+    }
+
+    fn entries(&mut self) -> Vec<(String, UTXOEntry)> {
+        let mut entries = Vec::new();
+
+        for (key, entry) in self
+            .store
+            .scan_prefix(ENTRY_PREFIX)
+            .expect("error scanning store")
+        {
+            entries.push((
+                key[ENTRY_PREFIX.len()..].to_string(),
+                UTXOEntry::deserialize(entry),
+            ));
         }
+
+        entries
+    }
+
+    pub fn best_block(&mut self) -> Option<String> {
+        self.store
+            .get(BEST_BLOCK_KEY)
+            .expect("error getting best block from store")
+            .map(|hash| String::from_utf8(hash).expect("error, best block is not UTF-8"))
     }
 
-    pub fn find_spendable_outputs(
+    fn set_best_block(&mut self, block_hash: &str) {
+        match self
+            .store
+            .insert(BEST_BLOCK_KEY.to_string(), block_hash.as_bytes().to_vec())
+        {
+            Ok(_) => (),
+            Err(err) => panic!("error while putting best block into store: {}", err),
+        };
+    }
+
+    fn insert(&mut self, key: String, entry: &UTXOEntry) {
+        let mut muhash = self.muhash();
+        muhash.insert(&entry_data(&key, entry));
+        self.set_muhash(&muhash);
+
+        match self.store.insert(
+            address_outpoint_key(entry.output().lock().hash(), &key),
+            Vec::new(),
+        ) {
+            Ok(_) => (),
+            Err(err) => panic!("error while updating address index in store: {}", err),
+        };
+
+        match self.store.insert(entry_key(&key), entry.serialize()) {
+            Ok(_) => (),
+            Err(err) => panic!("error while putting UTXOEntry data into store: {}", err),
+        };
+    }
+
+    // Rolling hash of the whole set, kept up to date as entries come and go
+    fn muhash(&mut self) -> MuHash {
+        match self
+            .store
+            .get(MUHASH_KEY)
+            .expect("error getting MuHash from store")
+        {
+            Some(muhash) => bincode::deserialize(&muhash[..]).expect("error decerializing MuHash"),
+            None => MuHash::new(),
+        }
+    }
+
+    fn set_muhash(&mut self, muhash: &MuHash) {
+        let muhash = bincode::serialize(muhash).expect("error serializing MuHash");
+
+        match self.store.insert(MUHASH_KEY.to_string(), muhash) {
+            Ok(_) => (),
+            Err(err) => panic!("error while putting MuHash into store: {}", err),
+        };
+    }
+
+    pub fn commitment(&mut self) -> String {
+        self.muhash().finalize()
+    }
+
+    // Commitment the set would have after connecting transactions in a block at
+    // height, an error if they spend outputs the set doesn't have
+    pub fn next_commitment(
         &mut self,
-        pub_key_hash: &[u8],
-        amount: i32,
-    ) -> (i32, HashMap<String, Vec<i32>>) {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
-
-        for key in self.store.keys().expect("error getting keys from store") {
-            let outs = match self
-                .store
-                .get(&key)
-                .expect("error getting TXOutputs from store")
-            {
-                Some(o) => match o {
-                    StoreValue::TXOutputs(outputs) => TXOutputs::deserialize(outputs),
-                    _ => panic!("wrong type returned from store, StoreValue::TXOutputs expected"),
-                },
-                None => panic!("error getting TXOutputs from store"),
-            };
+        transactions: &[Transaction],
+        height: i32,
+    ) -> Result<String, String> {
+        let mut muhash = self.muhash();
+        commit_transactions(&mut muhash, transactions, height, |key| self.get_entry(key))?;
+        Ok(muhash.finalize())
+    }
 
-            for (idx, out) in outs.outputs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += out.value();
-                    unspent_outputs
-                        .entry(key.clone())
-                        .or_insert(Vec::new())
-                        .push(idx as i32);
-                }
+    // Entries locked with the given key, read through the address index
+    fn address_entries(&mut self, pub_key_hash: &[u8]) -> Vec<(String, UTXOEntry)> {
+        let mut entries = Vec::new();
+
+        for key in self.address_outpoints(pub_key_hash) {
+            match self.get_entry(&key) {
+                Some(entry) => entries.push((key, entry)),
+                None => panic!("error, address index points at missing output {}", key),
             }
         }
 
-        (accumulated, unspent_outputs)
+        entries
+    }
+
+    pub fn find_spendable_coins(&mut self, pub_key_hash: &[u8]) -> Vec<Coin> {
+        let mut coins = Vec::new();
+
+        for (key, entry) in self.address_entries(pub_key_hash) {
+            let (tx_id, v_out) = parse_outpoint(&key);
+            coins.push(Coin::new(tx_id, v_out, entry.output().value()));
+        }
+
+        coins
+    }
+
+    pub fn select_coins(
+        &mut self,
+        pub_key_hash: &[u8],
+        amount: Amount,
+        selector: &dyn CoinSelector,
+    ) -> Option<Selection> {
+        let coins = self.find_spendable_coins(pub_key_hash);
+        selector.select(&coins, amount)
     }
 
     pub fn find_utxo(&mut self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
         let mut utxos = Vec::new();
 
-        for key in self.store.keys().expect("error getting keys from store") {
-            let outs = match self
-                .store
-                .get(&key)
-                .expect("error getting TXOutputs from store")
-            {
-                Some(o) => match o {
-                    StoreValue::TXOutputs(outputs) => TXOutputs::deserialize(outputs),
-                    _ => panic!("wrong type returned from store, StoreValue::TXOutputs expected"),
-                },
-                None => panic!("error getting TXOutputs from store"),
-            };
+        for (_, entry) in self.address_entries(pub_key_hash) {
+            utxos.push(entry.output().clone());
+        }
+
+        utxos
+    }
+
+    pub fn count_outputs(&mut self) -> usize {
+        self.entries().len()
+    }
 
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out);
+    fn reset(&mut self) {
+        self.store.clear();
+        self.migrate();
+    }
+
+    pub fn reindex(&mut self, bc: &mut Blockchain) {
+        require_full_chain(bc);
+        self.reset();
+
+        for block_hash in bc.get_block_hashes().iter().rev() {
+            let block = bc.get_block(block_hash);
+            self.update(&block);
+        }
+    }
+
+    // Rebuilds the set from the chain in memory and compares it with the store
+    pub fn verify(&mut self, bc: &mut Blockchain) -> UTXODiff {
+        require_full_chain(bc);
+        let mut expected = BTreeMap::new();
+
+        for block_hash in bc.get_block_hashes().iter().rev() {
+            apply_block(&mut expected, &bc.get_block(block_hash));
+        }
+
+        let mut stored: BTreeMap<String, UTXOEntry> = self.entries().into_iter().collect();
+        let mut missing = Vec::new();
+        let mut mismatched = Vec::new();
+
+        for (key, entry) in expected {
+            match stored.remove(&key) {
+                Some(found) => {
+                    if entry_data(&key, &found) != entry_data(&key, &entry) {
+                        mismatched.push((key, entry, found));
+                    }
                 }
+                None => missing.push((key, entry)),
             }
         }
 
-        utxos
+        UTXODiff {
+            missing,
+            extra: stored.into_iter().collect(),
+            mismatched,
+        }
+    }
+
+    // Fixes only the entries in diff, then recomputes the rolling hash over
+    // the whole set since it can't be trusted once entries were wrong
+    pub fn repair(&mut self, diff: &UTXODiff, bc: &Blockchain) {
+        let wrong = diff.extra.iter().map(|(key, _)| key);
+
+        for key in wrong.chain(diff.mismatched.iter().map(|(key, _, _)| key)) {
+            let (tx_id, v_out) = parse_outpoint(key);
+            self.spend(tx_id, v_out);
+        }
+
+        let right = diff.missing.iter().map(|(key, entry)| (key, entry));
+
+        for (key, entry) in right.chain(diff.mismatched.iter().map(|(key, entry, _)| (key, entry)))
+        {
+            self.insert(key.clone(), entry);
+        }
+
+        let mut muhash = MuHash::new();
+
+        for (key, entry) in self.entries() {
+            muhash.insert(&entry_data(&key, &entry));
+        }
+
+        self.set_muhash(&muhash);
+        self.set_best_block(bc.tip());
+        self.store.flush();
     }
 
-    pub fn count_transactions(&mut self) -> usize {
-        self.store.keys().unwrap().len()
+    pub fn info(&mut self) -> UTXOSetInfo {
+        let mut muhash = MuHash::new();
+        let mut total = Amount::ZERO;
+        let mut serialized_size = 0;
+        let entries = self.entries();
+
+        for (key, entry) in &entries {
+            let data = entry_data(key, entry);
+            muhash.insert(&data);
+            serialized_size += data.len();
+            total = total
+                .checked_add(entry.output().value())
+                .expect("error, UTXO set total exceeds the supply limit");
+        }
+
+        UTXOSetInfo {
+            best_block: self.best_block(),
+            outputs: entries.len(),
+            total,
+            serialized_size,
+            hash: muhash.finalize(),
+        }
     }
 
-    pub fn reindex(&mut self, node_id: &str, bc: &mut Blockchain) {
-        let db_file = Box::leak(Box::new(format!("utxo_set_{}.db", node_id)));
-        if Path::new(&db_file).exists() {
-            let _ = fs::remove_file(&db_file);
+    pub fn dump(&mut self, bc: &mut Blockchain) -> UTXOSnapshot {
+        let block_hash = match self.best_block() {
+            Some(block_hash) => block_hash,
+            None => panic!("error, UTXO set is empty"),
+        };
+        let utxo: BTreeMap<String, UTXOEntry> = self.entries().into_iter().collect();
+        let mut headers = Vec::new();
+        let mut hash = block_hash.clone();
+
+        while !hash.is_empty() {
+            let header = bc.get_header(&hash);
+            hash = header.prev_block_hash().to_string();
+            headers.push(header);
         }
 
-        self.store = KV::<String, StoreValue>::new(db_file).expect("error opening utxo set store");
-        let utxo = bc.find_utxo();
+        headers.reverse();
 
-        for (tx_id, outs) in utxo {
-            match self
-                .store
-                .insert(tx_id, StoreValue::TXOutputs(outs.serialize()))
-            {
-                Ok(_) => (),
-                Err(err) => panic!("error while putting TXOutputs data into store: {}", err),
-            };
+        UTXOSnapshot {
+            height: bc.get_header(&block_hash).height(),
+            block_hash,
+            content_hash: content_hash(&utxo),
+            entries: utxo.into_iter().collect(),
+            headers,
+        }
+    }
+
+    // Replaces the set with the snapshot and connects the blocks between the
+    // snapshot and the tip. Only the headers up to the snapshot are needed, if
+    // they go past the tip the snapshot block becomes the tip. The snapshot
+    // stays pending until validate_snapshot has replayed the chain up to it.
+    pub fn load(&mut self, snapshot: UTXOSnapshot, bc: &mut Blockchain) {
+        let UTXOSnapshot {
+            block_hash,
+            content_hash: expected_hash,
+            entries,
+            headers,
+            ..
+        } = snapshot;
+        let utxo: BTreeMap<String, UTXOEntry> = entries.into_iter().collect();
+
+        if content_hash(&utxo) != expected_hash {
+            panic!("error, UTXO snapshot content doesn't match its hash");
+        }
+
+        if headers.last().map(|header| header.hash()) != Some(&block_hash[..]) {
+            panic!(
+                "error, UTXO snapshot headers don't end at block {}",
+                block_hash
+            );
+        }
+
+        if let Err(err) = bc.add_headers(&headers) {
+            panic!(
+                "error, UTXO snapshot doesn't fit the current chain: {}",
+                err
+            );
+        }
+
+        let mut to_connect = Vec::new();
+
+        for height in headers.len() as i32..=bc.get_best_height() {
+            match bc.get_block_hash(height) {
+                Some(hash) if bc.has_block(&hash) => to_connect.push(hash),
+                _ => panic!(
+                    "error, block at height {} above the snapshot is missing",
+                    height
+                ),
+            }
         }
+
+        let mut muhash = MuHash::new();
+
+        for (key, entry) in &utxo {
+            muhash.insert(&entry_data(key, entry));
+        }
+
+        if muhash.finalize() != bc.get_header(&block_hash).utxo_commitment() {
+            panic!(
+                "error, UTXO snapshot doesn't match the commitment of block {}",
+                block_hash
+            );
+        }
+
+        self.reset();
+
+        for (key, entry) in utxo {
+            self.insert(key, &entry);
+        }
+
+        self.set_best_block(&block_hash);
+        let pending = bincode::serialize(&(&block_hash, &expected_hash))
+            .expect("error serializing pending snapshot");
+
+        match self.store.insert(PENDING_SNAPSHOT_KEY.to_string(), pending) {
+            Ok(_) => (),
+            Err(err) => panic!("error while putting pending snapshot into store: {}", err),
+        };
+
+        for block_hash in &to_connect {
+            let block = bc.get_block(block_hash);
+            self.update(&block);
+        }
+    }
+
+    // Block hash and content hash of a loaded snapshot that hasn't been validated yet
+    pub fn pending_snapshot(&mut self) -> Option<(String, String)> {
+        self.store
+            .get(PENDING_SNAPSHOT_KEY)
+            .expect("error getting pending snapshot from store")
+            .map(|pending| {
+                bincode::deserialize(&pending[..]).expect("error decerializing pending snapshot")
+            })
+    }
+
+    pub fn clear_pending_snapshot(&mut self) {
+        match self.store.remove(PENDING_SNAPSHOT_KEY) {
+            Ok(_) => (),
+            Err(err) => panic!("error while removing pending snapshot from store: {}", err),
+        };
     }
 
     pub fn update(&mut self, block: &Block) {
+        let mut undo = BlockUndo { spent: Vec::new() };
+
         for tx in block.transactions() {
             if !tx.is_coinbase() {
                 for tx_in in tx.v_in() {
-                    let outs = match self
-                        .store
-                        .get(&tx_in.tx_id().to_string())
-                        .expect("error getting TXOutputs from store")
-                    {
-                        Some(o) => match o {
-                            StoreValue::TXOutputs(outputs) => TXOutputs::deserialize(outputs),
-                            _ => panic!(
-                                "wrong type returned from store, StoreValue::TXOutputs expected"
-                            ),
-                        },
-                        None => panic!("error getting TXOutputs from store"),
-                    };
-                    let mut updated_outs = TXOutputs::new(Vec::new());
-
-                    for (idx, out) in outs.outputs.iter().enumerate() {
-                        if idx as i32 != tx_in.v_out() {
-                            updated_outs.outputs.push(out.clone());
-                        }
-                    }
+                    let entry = self.spend(tx_in.tx_id(), tx_in.v_out());
+                    undo.spent
+                        .push((outpoint(tx_in.tx_id(), tx_in.v_out()), entry));
+                }
+            }
 
-                    if updated_outs.outputs.is_empty() {
-                        match self.store.remove(&tx_in.tx_id().to_string()) {
-                            Ok(_) => (),
-                            Err(err) => {
-                                panic!("error while removing TXOutputs data from store: {}", err)
-                            }
-                        };
-                    } else {
-                        match self.store.insert(
-                            tx_in.tx_id().to_string(),
-                            StoreValue::TXOutputs(updated_outs.serialize()),
-                        ) {
-                            Ok(_) => (),
-                            Err(err) => {
-                                panic!("error while putting TXOutputs data into store: {}", err)
-                            }
-                        };
-                    }
+            for (idx, out) in tx.v_out().iter().enumerate() {
+                if out.is_data_carrier() {
+                    continue;
                 }
+
+                let entry = UTXOEntry::new(out.clone(), block.height(), tx.is_coinbase());
+                self.insert(outpoint(tx.id(), idx as i32), &entry);
             }
+        }
+
+        match self
+            .store
+            .insert(format!("{}{}", UNDO_PREFIX, block.hash()), undo.serialize())
+        {
+            Ok(_) => (),
+            Err(err) => panic!("error while putting BlockUndo data into store: {}", err),
+        };
+        self.set_best_block(block.hash());
+        self.store.maybe_flush();
+    }
 
-            let mut new_outputs = TXOutputs::new(Vec::new());
+    // Writes the changes cached so far to the store
+    pub fn flush(&mut self) {
+        self.store.flush();
+    }
 
-            for out in tx.v_out() {
-                new_outputs.outputs.push(out.clone());
+    // Finishes a commit a crash interrupted. The set is flushed at block
+    // boundaries only, so it sits on some block: blocks off the active chain are
+    // disconnected and the ones up to the tip connected. Without a usable best
+    // block or undo data the set is rebuilt. Returns false if nothing was done.
+    pub fn recover(&mut self, bc: &mut Blockchain) -> bool {
+        let mut block_hash = match self.best_block() {
+            Some(block_hash) => block_hash,
+            None => {
+                self.reindex(bc);
+                return true;
             }
+        };
 
-            match self.store.insert(
-                tx.id().to_string(),
-                StoreValue::TXOutputs(new_outputs.serialize()),
-            ) {
+        if block_hash == bc.tip() {
+            return false;
+        }
+
+        while !bc.is_on_active_chain(&block_hash) {
+            if !bc.has_block(&block_hash) || !self.has_undo(&block_hash) {
+                self.reindex(bc);
+                return true;
+            }
+
+            let block = bc.get_block(&block_hash);
+            self.disconnect_block(&block);
+            block_hash = block.prev_block_hash().to_string();
+        }
+
+        let height = bc.get_header(&block_hash).height();
+
+        for height in height + 1..=bc.get_best_height() {
+            match bc.get_block_by_height(height) {
+                Some(block) => self.update(&block),
+                None => panic!("error, no block at height {} of the active chain", height),
+            }
+        }
+
+        self.store.flush();
+        true
+    }
+
+    // Undo data of pruned blocks goes with them, they can't be disconnected anymore
+    pub fn prune_undo(&mut self, block_hashes: &[String]) {
+        for block_hash in block_hashes {
+            match self.store.remove(&format!("{}{}", UNDO_PREFIX, block_hash)) {
                 Ok(_) => (),
-                Err(err) => panic!("error while putting TXOutputs data into store: {}", err),
+                Err(err) => panic!("error while removing BlockUndo data from store: {}", err),
             };
         }
+
+        self.store.flush();
+    }
+
+    // Outputs of tx_id that are still unspent, as a transaction holding them
+    // at their positions
+    pub fn unspent_transaction(&mut self, tx_id: &str) -> Option<Transaction> {
+        let outputs: Vec<(usize, TXOutput)> = self
+            .store
+            .scan_prefix(&entry_key(&format!("{}:", tx_id)))
+            .expect("error getting UTXOEntries from store")
+            .into_iter()
+            .map(|(key, entry)| {
+                (
+                    parse_outpoint(&key).1 as usize,
+                    UTXOEntry::deserialize(entry).output().clone(),
+                )
+            })
+            .collect();
+
+        if outputs.is_empty() {
+            return None;
+        }
+
+        Some(Transaction::from_unspent_outputs(tx_id, outputs))
+    }
+
+    fn has_undo(&mut self, block_hash: &str) -> bool {
+        self.store
+            .get(&format!("{}{}", UNDO_PREFIX, block_hash))
+            .expect("error getting BlockUndo from store")
+            .is_some()
+    }
+
+    // Reverses update: drops the outputs the block created and restores the ones it spent
+    pub fn disconnect_block(&mut self, block: &Block) {
+        match self.best_block() {
+            Some(ref hash) if hash == block.hash() => (),
+            _ => panic!(
+                "error, block {} is not the best block of the UTXO set",
+                block.hash()
+            ),
+        }
+
+        let undo_key = format!("{}{}", UNDO_PREFIX, block.hash());
+        let undo = match self
+            .store
+            .get(&undo_key)
+            .expect("error getting BlockUndo from store")
+        {
+            Some(undo) => BlockUndo::deserialize(undo),
+            None => panic!("error, no undo data for block {}", block.hash()),
+        };
+
+        let mut spent = undo.spent;
+
+        for tx in block.transactions().iter().rev() {
+            for (idx, out) in tx.v_out().iter().enumerate() {
+                if !out.is_data_carrier() {
+                    self.spend(tx.id(), idx as i32);
+                }
+            }
+
+            if !tx.is_coinbase() {
+                for _ in tx.v_in() {
+                    let (key, entry) = spent.pop().expect("error, undo data is incomplete");
+                    self.insert(key, &entry);
+                }
+            }
+        }
+
+        match self.store.remove(&undo_key) {
+            Ok(_) => (),
+            Err(err) => panic!("error while removing BlockUndo data from store: {}", err),
+        };
+        self.set_best_block(block.prev_block_hash());
+        self.store.maybe_flush();
     }
 }
@@ -5,6 +5,7 @@ use ring::{
 };
 
 const VERSION: u8 = 0;
+pub const POLICY_VERSION: u8 = 5;
 pub const ADDRESS_CHECKSUM_LEN: usize = 4;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,8 +31,12 @@ impl Wallet {
 
     pub fn get_address(&self) -> String {
         let pub_key_hash = Self::hash_pub_key(&self.public_key[..]);
-        let mut payload = vec![VERSION];
-        payload.extend(pub_key_hash);
+        Self::address_from_hash(VERSION, &pub_key_hash)
+    }
+
+    pub fn address_from_hash(version: u8, hash: &[u8]) -> String {
+        let mut payload = vec![version];
+        payload.extend(hash);
         let checksum = Self::checksum(&payload);
         payload.extend(checksum);
         bs58::encode(&payload).into_string()
@@ -1,6 +1,8 @@
+use crate::datadir::DataDir;
+use crate::storage::Store;
 use crate::wallet::Wallet;
 
-use typedb::{value, KV};
+use typedb::value;
 
 value!(
     enum StoreValue {
@@ -10,14 +12,14 @@ value!(
 );
 
 pub struct Wallets {
-    store: KV<String, StoreValue>,
+    store: Store<StoreValue>,
 }
 
 impl Wallets {
-    pub fn new(node_id: &str) -> Wallets {
-        let db_file = Box::leak(Box::new(format!("wallets_{}.db", node_id)));
-        let store = KV::<String, StoreValue>::new(db_file).expect("error opening wallet store");
-        Wallets { store }
+    pub fn new(data_dir: &DataDir) -> Wallets {
+        Wallets {
+            store: Store::open("wallets", data_dir),
+        }
     }
 
     pub fn create_wallet(&mut self) -> String {